@@ -0,0 +1,347 @@
+//! Async counterparts of the capture, reader and replayer for tokio applications,
+//! enabled with the `async` feature. The BBIN format and replay rules are shared
+//! with the sync versions.
+
+use crate::bbin_reader::{
+    data_end_from_trailer, decode_record, is_legacy_index, legacy_index_offset, read_file_header, read_record_header,
+    stop_at_truncation, LEGACY_FOOTER_LEN, TRAILER_LEN,
+};
+use crate::bbin_replayer::{frame_to_send, replay_delay, report_replayed};
+use crate::blackboxer::BlackBoxer;
+use crate::connection::Endpoint;
+use crate::error::BlackboxError;
+use crate::session::{CaptureHandle, CaptureStats, CaptureSummary};
+use crate::sink::{ChannelSink, SinkErrorPolicy};
+use crate::types::{BbinHeader, BbinRecord, LoggedMessage, BBIN_LEGACY_VERSION};
+use futures_core::Stream;
+use log::info;
+use std::io::{self, SeekFrom};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::net::{lookup_host, TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+
+/// How much of the file is read at a time.
+const READ_CHUNK: usize = 64 * 1024;
+
+/// Upper bound on the bincode size of a `LoggedMessageHeader`.
+const MAX_RECORD_HEADER: usize = 64;
+
+/// Reads a BBIN file like `BbinReader`, without blocking the runtime.
+pub struct AsyncBbinReader {
+    file: File,
+    header: BbinHeader,
+    /// Bytes read from the file but not yet consumed, starting at `start`.
+    buf: Vec<u8>,
+    start: usize,
+    /// Record bytes left in the file after `buf`.
+    remaining: u64,
+    /// File offset of `buf[0]`.
+    consumed: u64,
+}
+
+impl AsyncBbinReader {
+    pub async fn new(file_path: &str) -> Result<Self, BlackboxError> {
+        let mut file = File::open(file_path).await?;
+        let file_len = file.metadata().await?.len();
+        let mut data_end = file_len;
+        if let Some(trailer_pos) = file_len.checked_sub(TRAILER_LEN as u64) {
+            file.seek(SeekFrom::Start(trailer_pos)).await?;
+            let mut trailer = [0u8; TRAILER_LEN];
+            file.read_exact(&mut trailer).await?;
+            data_end = data_end_from_trailer(&trailer, trailer_pos)?;
+            file.seek(SeekFrom::Start(0)).await?;
+        }
+
+        let mut reader = AsyncBbinReader {
+            file,
+            header: BbinHeader { magic: [0; 4], version: 0, start_timestamp: 0, previous_segment: None },
+            buf: Vec::new(),
+            start: 0,
+            remaining: data_end,
+            consumed: 0,
+        };
+        reader.fill(READ_CHUNK).await?;
+        let mut unread = &reader.buf[reader.start..];
+        reader.header = read_file_header(&mut unread)?;
+        reader.start = reader.buf.len() - unread.len();
+        if reader.header.version == BBIN_LEGACY_VERSION {
+            reader.find_legacy_data_end(file_len).await?;
+        }
+        Ok(reader)
+    }
+
+    /// The file header, including the segment this file continues, if any.
+    pub fn file_header(&self) -> &BbinHeader {
+        &self.header
+    }
+
+    /// Reads the next record, or `None` once the end of the logged data is reached,
+    /// with the same rules as `BbinReader::next_record`.
+    pub async fn next_record(&mut self) -> Result<Option<BbinRecord>, BlackboxError> {
+        if !self.fill(1).await? {
+            return Ok(None);
+        }
+        let offset = self.offset();
+        self.fill(MAX_RECORD_HEADER).await?;
+        let mut unread = &self.buf[self.start..];
+        let header = match read_record_header(&mut unread, self.header.version) {
+            Ok(h) => h,
+            Err(e) => {
+                match *e {
+                    bincode::ErrorKind::Io(ref io_err) if io_err.kind() == io::ErrorKind::UnexpectedEof => {
+                        return Err(self.truncated_at(offset));
+                    }
+                    _ => return Err(e.into()),
+                }
+            }
+        };
+        let header_len = self.buf.len() - self.start - unread.len();
+        let record_len = header_len + header.msg_len as usize;
+        if !self.fill(record_len).await? {
+            return Err(self.truncated_at(offset));
+        }
+        let bytes = self.buf[self.start + header_len..self.start + record_len].to_vec();
+        self.start += record_len;
+        decode_record(header, bytes).map(Some)
+    }
+
+    /// Stops at the index of a finalized `BBIN_LEGACY_VERSION` file, as `BbinReader` does.
+    async fn find_legacy_data_end(&mut self, file_len: u64) -> io::Result<()> {
+        let Some(footer_pos) = file_len.checked_sub(LEGACY_FOOTER_LEN as u64) else {
+            return Ok(());
+        };
+        let buffered_end = self.consumed + self.buf.len() as u64;
+        self.file.seek(SeekFrom::Start(footer_pos)).await?;
+        let mut footer = [0u8; LEGACY_FOOTER_LEN];
+        self.file.read_exact(&mut footer).await?;
+        if let Some(index_offset) = legacy_index_offset(&footer, self.offset(), footer_pos) {
+            self.file.seek(SeekFrom::Start(index_offset)).await?;
+            let mut index = vec![0u8; (footer_pos - index_offset) as usize];
+            self.file.read_exact(&mut index).await?;
+            if is_legacy_index(&index) {
+                self.buf.truncate((index_offset - self.consumed) as usize);
+                self.remaining = index_offset.saturating_sub(buffered_end);
+            }
+        }
+        self.file.seek(SeekFrom::Start(buffered_end)).await?;
+        Ok(())
+    }
+
+    /// File offset of the next unconsumed byte.
+    fn offset(&self) -> u64 {
+        self.consumed + self.start as u64
+    }
+
+    fn truncated_at(&mut self, offset: u64) -> BlackboxError {
+        self.buf.clear();
+        self.start = 0;
+        self.remaining = 0;
+        BlackboxError::TruncatedRecord { offset }
+    }
+
+    /// Makes sure at least `len` unconsumed bytes are buffered. Returns false if the
+    /// logged data ends first.
+    async fn fill(&mut self, len: usize) -> io::Result<bool> {
+        while self.buf.len() - self.start < len && self.remaining > 0 {
+            self.buf.drain(..self.start);
+            self.consumed += self.start as u64;
+            self.start = 0;
+            let chunk = READ_CHUNK.min(self.remaining as usize);
+            let filled = self.buf.len();
+            self.buf.resize(filled + chunk, 0);
+            let read = self.file.read(&mut self.buf[filled..]).await?;
+            self.buf.truncate(filled + read);
+            if read == 0 {
+                self.remaining = 0;
+            }
+            self.remaining -= read as u64;
+        }
+        Ok(self.buf.len() - self.start >= len)
+    }
+}
+
+/// Replays a BBIN file like `BbinReplayer`, pacing with the tokio timer.
+///
+/// Serial targets are not supported. A `udpin` target waits for the first datagram
+/// to learn where to send.
+pub struct AsyncBbinReplayer {
+    reader: AsyncBbinReader,
+    link: AsyncLink,
+    target: String,
+    verbatim: bool,
+}
+
+enum AsyncLink {
+    Tcp(TcpStream),
+    Udp { socket: UdpSocket, dest: SocketAddr },
+}
+
+impl AsyncLink {
+    async fn open(endpoint: &Endpoint) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::TcpOut(addr) => Ok(AsyncLink::Tcp(TcpStream::connect(addr).await?)),
+            Endpoint::TcpIn(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                Ok(AsyncLink::Tcp(listener.accept().await?.0))
+            }
+            Endpoint::UdpIn(addr) => {
+                let socket = UdpSocket::bind(addr).await?;
+                let (_, dest) = socket.peek_from(&mut [0u8; 1]).await?;
+                Ok(AsyncLink::Udp { socket, dest })
+            }
+            Endpoint::UdpOut(addr) | Endpoint::UdpBcast(addr) => {
+                let dest = lookup_host(addr.as_str()).await?.next()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Address did not resolve"))?;
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                socket.set_broadcast(matches!(endpoint, Endpoint::UdpBcast(_)))?;
+                Ok(AsyncLink::Udp { socket, dest })
+            }
+            Endpoint::Serial { .. } => {
+                Err(io::Error::new(io::ErrorKind::Unsupported, "Async replay does not support serial targets"))
+            }
+        }
+    }
+}
+
+impl AsyncBbinReplayer {
+    /// Opens `file_path` for replay towards `target`, a connection string such as
+    /// `tcpout:127.0.0.1:5760` or `udpout:127.0.0.1:14550`.
+    pub async fn new(file_path: &str, target: &str) -> Result<Self, BlackboxError> {
+        let reader = AsyncBbinReader::new(file_path).await?;
+        let endpoint: Endpoint = target.parse()?;
+        let link = AsyncLink::open(&endpoint)
+            .await
+            .map_err(|source| BlackboxError::Link { endpoint: endpoint.to_string(), source })?;
+        info!("Connected to {}", endpoint);
+        Ok(AsyncBbinReplayer { reader, link, target: endpoint.to_string(), verbatim: true })
+    }
+
+    /// Chooses between sending each logged frame byte-for-byte (the default) and
+    /// re-encoding it, as `BbinReplayer::set_verbatim`.
+    pub fn set_verbatim(&mut self, verbatim: bool) {
+        self.verbatim = verbatim;
+    }
+
+    /// Replays the logged downlink frames, as `BbinReplayer::replay_messages`.
+    pub async fn replay_messages(&mut self, filter_msg_type: Option<&str>, realtime: bool, speed: f32) -> Result<(), BlackboxError> {
+        if speed <= 0.0 {
+            return Err(BlackboxError::InvalidConfig("Speed must be a positive value".to_string()));
+        }
+
+        let mut prev_time: Option<i64> = None;
+
+        while let Some(record) = stop_at_truncation(self.reader.next_record().await)? {
+            let Some(frame) = frame_to_send(&record, filter_msg_type, self.verbatim)? else {
+                continue;
+            };
+            if realtime {
+                if let Some(delay) = replay_delay(prev_time, record.header().timestamp, speed) {
+                    tokio::time::sleep(delay).await;
+                }
+                prev_time = Some(record.header().timestamp);
+            }
+            let sent = match &mut self.link {
+                AsyncLink::Tcp(stream) => stream.write_all(&frame).await,
+                AsyncLink::Udp { socket, dest } => socket.send_to(&frame, *dest).await.map(|_| ()),
+            };
+            sent.map_err(|source| BlackboxError::Link { endpoint: self.target.clone(), source })?;
+            report_replayed(&record);
+        }
+
+        info!("Replay complete");
+        Ok(())
+    }
+}
+
+/// A capture running on its own thread whose decoded messages arrive as a `Stream`,
+/// returned by `BlackBoxer::start_stream`. The stream ends once the capture does.
+pub struct CaptureStream {
+    handle: CaptureHandle,
+    messages: mpsc::Receiver<LoggedMessage>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl BlackBoxer {
+    /// Like `start`, but delivers decoded messages and arm state changes as a stream.
+    /// At most `capacity` messages are buffered; the log is written either way, and
+    /// messages arriving while the buffer is full are dropped and counted.
+    pub fn start_stream(mut self, capacity: usize) -> Result<CaptureStream, BlackboxError> {
+        let (tx, messages) = mpsc::channel(capacity.max(1));
+        let dropped = Arc::new(AtomicU64::new(0));
+        self.add_sink(ChannelSink::tokio(tx, dropped.clone()), SinkErrorPolicy::Remove);
+        Ok(CaptureStream { handle: self.start()?, messages, dropped })
+    }
+}
+
+impl CaptureStream {
+    /// Stops the capture and waits, off the runtime, until the log is finalized.
+    pub async fn stop(self) -> Result<CaptureSummary, BlackboxError> {
+        let handle = self.handle;
+        tokio::task::spawn_blocking(move || handle.stop())
+            .await
+            .map_err(|_| BlackboxError::CapturePanicked)?
+    }
+
+    /// Stops writing to the log until `resume`, as `CaptureHandle::pause`.
+    pub fn pause(&self) {
+        self.handle.pause();
+    }
+
+    pub fn resume(&self) {
+        self.handle.resume();
+    }
+
+    pub fn stats(&self) -> CaptureStats {
+        self.handle.stats()
+    }
+
+    /// Messages discarded because the stream was not polled fast enough.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Waits for the next message, as `StreamExt::next` would.
+    pub async fn recv(&mut self) -> Option<LoggedMessage> {
+        self.messages.recv().await
+    }
+}
+
+impl Stream for CaptureStream {
+    type Item = LoggedMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<LoggedMessage>> {
+        self.messages.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bbin_reader::tests::{heartbeat, temp_path, write_legacy_file};
+
+    #[test]
+    fn reads_legacy_files() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        for finalized in [false, true] {
+            let path = temp_path(&format!("async-legacy-{}", finalized));
+            write_legacy_file(&path, finalized);
+            let bytes: Vec<Vec<u8>> = runtime.block_on(async {
+                let mut reader = AsyncBbinReader::new(path.to_str().unwrap()).await.unwrap();
+                assert_eq!(reader.file_header().version, BBIN_LEGACY_VERSION);
+                let mut bytes = Vec::new();
+                while let Some(record) = reader.next_record().await.unwrap() {
+                    bytes.push(record.bytes().to_vec());
+                }
+                bytes
+            });
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(bytes, (0..3).map(|sequence| heartbeat(sequence).1).collect::<Vec<_>>());
+        }
+    }
+}
@@ -1,111 +1,383 @@
-use crate::types::{BbinHeader, LoggedMessage, LoggedMessageHeader};
+use crate::error::BlackboxError;
+use crate::types::{BbinHeader, BbinIndexEntry, BbinRecord, LoggedMessage, LoggedMessageHeader, RecordKind, UndecodedReason, BBIN_INDEX_MAGIC, BBIN_LEGACY_VERSION, BBIN_MIN_VERSION, BBIN_VERSION};
 use mavlink::ardupilotmega::MavMessage;
-use mavlink::read_v2_msg;
+use mavlink::{read_versioned_msg, MavHeader, MavlinkVersion};
+use serde::Deserialize;
 use std::fs::File;
-use std::io::{self, BufReader, Read};
-use bincode::deserialize_from;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use bincode::{deserialize, deserialize_from};
+use log::{debug, info, warn};
 
 pub struct BbinReader {
     reader: BufReader<File>,
+    header: BbinHeader,
+    /// Offset where the records stop and the index of a finalized file begins.
+    data_end: u64,
 }
 
 impl BbinReader {
-    pub fn new(file_path: &str) -> io::Result<Self> {
-        let file = File::open(file_path)?;
+    pub fn new(file_path: &str) -> Result<Self, BlackboxError> {
+        let mut file = File::open(file_path)?;
+        let mut data_end = find_data_end(&mut file)?;
         let mut reader = BufReader::new(file);
-        let header: BbinHeader = deserialize_from(&mut reader)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        if header.magic != *b"BBIN" {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid BBIN file magic"));
+        let header = read_file_header(&mut reader)?;
+        if header.version == BBIN_LEGACY_VERSION {
+            let records_start = reader.stream_position()?;
+            data_end = legacy_data_end(reader.get_mut(), records_start)?;
+            reader.seek(SeekFrom::Start(records_start))?;
         }
-        Ok(BbinReader { reader })
-    }
-
-    pub fn read_messages(&mut self, filter_msg_type: Option<&str>, show: bool) -> io::Result<()> {
-        loop {
-            let header: LoggedMessageHeader = match deserialize_from(&mut self.reader) {
-                Ok(h) => h,
-                Err(e) => {
-                    match *e {
-                        bincode::ErrorKind::Io(ref io_err) if io_err.kind() == io::ErrorKind::UnexpectedEof => {
-                            if show {
-                                println!("Reached EOF or incomplete message, stopping.");
-                            }
-                            break;
-                        }
-                        _ => {
-                            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
-                        }
+        Ok(BbinReader { reader, header, data_end })
+    }
+
+    /// The file header, including the segment this file continues, if any.
+    pub fn file_header(&self) -> &BbinHeader {
+        &self.header
+    }
+
+    /// Reads the next record, or `None` once the end of the logged data is reached.
+    ///
+    /// A message record that no longer decodes is returned as `Undecoded` with
+    /// `UndecodedReason::ParseError`. A log whose capture was cut off mid-record ends
+    /// with `BlackboxError::TruncatedRecord`, after which `None` is returned.
+    pub fn next_record(&mut self) -> Result<Option<BbinRecord>, BlackboxError> {
+        let offset = self.reader.stream_position()?;
+        if offset >= self.data_end {
+            return Ok(None);
+        }
+
+        let header = match read_record_header(&mut self.reader, self.header.version) {
+            Ok(h) => h,
+            Err(e) => {
+                match *e {
+                    bincode::ErrorKind::Io(ref io_err) if io_err.kind() == io::ErrorKind::UnexpectedEof => {
+                        return Err(self.truncated_at(offset));
                     }
+                    _ => return Err(e.into()),
                 }
-            };
+            }
+        };
 
-            let mut msg_buf = vec![0u8; header.msg_len as usize];
-            self.reader.read_exact(&mut msg_buf)?;
+        let mut bytes = vec![0u8; header.msg_len as usize];
+        match self.reader.read_exact(&mut bytes) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Err(self.truncated_at(offset)),
+            Err(e) => return Err(e.into()),
+        }
+
+        decode_record(header, bytes).map(Some)
+    }
+
+    fn truncated_at(&mut self, offset: u64) -> BlackboxError {
+        self.data_end = offset;
+        BlackboxError::TruncatedRecord { offset }
+    }
 
-            let mut packet: &[u8] = &msg_buf;
-            match read_v2_msg::<MavMessage, _>(&mut packet) {
-                Ok((_hdr, msg)) => {
-                    let msg_type_str = format!("{:?}", msg);
+    /// Reads the whole log, printing its records to stdout if `show` is set; with a
+    /// filter, only messages whose type contains it are printed.
+    pub fn read_messages(&mut self, filter_msg_type: Option<&str>, show: bool) -> Result<(), BlackboxError> {
+        if let Some(previous) = &self.header.previous_segment {
+            info!(previous = previous.as_str(); "Log continues from {}", previous);
+        }
+        while let Some(record) = stop_at_truncation(self.next_record())? {
+            match record {
+                BbinRecord::Message { header, message, .. } => {
+                    let msg_type_str = format!("{:?}", message);
                     if show {
                         if let Some(filter) = filter_msg_type {
                             if msg_type_str.contains(filter) {
-                                println!("{} message: {:?}\nTimestamp: {}", filter, msg, header.timestamp);
+                                println!("{} message: {:?}\nTimestamp: {} (source {}, {})", filter, message, header.timestamp, header.source, header.direction);
                             }
                         } else {
-                            println!("Message: {:?}\nTimestamp: {}", msg, header.timestamp);
+                            println!("Message: {:?}\nTimestamp: {} (source {}, {})", message, header.timestamp, header.source, header.direction);
                         }
                     }
                 }
-                Err(e) => {
-                    eprintln!("Failed to decode MAVLink message: {:?}", e);
+                BbinRecord::Undecoded { header, reason, bytes } => {
+                    if show && filter_msg_type.is_none() {
+                        println!("Undecoded frame ({}): {:02X?}\nTimestamp: {} (source {}, {})", reason, bytes, header.timestamp, header.source, header.direction);
+                    }
+                }
+                BbinRecord::Event { header, event, bytes } => {
+                    if show && filter_msg_type.is_none() {
+                        println!("Event: {} ({})\nTimestamp: {} (source {}, {})", event, String::from_utf8_lossy(&bytes), header.timestamp, header.source, header.direction);
+                    }
+                }
+                BbinRecord::Stats { header, stats, .. } => {
+                    if show && filter_msg_type.is_none() {
+                        println!("Statistics: {:?}\nTimestamp: {}", stats, header.timestamp);
+                    }
                 }
             }
         }
+        debug!("Reached end of logged data");
         Ok(())
     }
 
-    pub fn read_and_collect(&mut self, filter_msg_type: Option<&str>) -> io::Result<Vec<LoggedMessage>> {
+    pub fn read_and_collect(&mut self, filter_msg_type: Option<&str>) -> Result<Vec<LoggedMessage>, BlackboxError> {
         let mut messages = Vec::new();
 
-        loop {
-            let header: LoggedMessageHeader = match deserialize_from(&mut self.reader) {
-                Ok(h) => h,
-                Err(e) => {
-                    match *e {
-                        bincode::ErrorKind::Io(ref io_err) if io_err.kind() == io::ErrorKind::UnexpectedEof => {
-                            break;
-                        }
-                        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
-                    }
-                }
-            };
-
-            let mut msg_buf = vec![0u8; header.msg_len as usize];
-            self.reader.read_exact(&mut msg_buf)?;
-
-            let mut packet: &[u8] = &msg_buf;
-            match read_v2_msg::<MavMessage, _>(&mut packet) {
-                Ok((_hdr, msg)) => {
-                    let msg_type_str = format!("{:?}", msg);
-                    if let Some(filter) = filter_msg_type {
-                        if !msg_type_str.contains(filter) {
-                            continue;
-                        }
+        while let Some(record) = stop_at_truncation(self.next_record())? {
+            if let BbinRecord::Message { header, message, .. } = record {
+                let msg_type_str = format!("{:?}", message);
+                if let Some(filter) = filter_msg_type {
+                    if !msg_type_str.contains(filter) {
+                        continue;
                     }
-                    messages.push(LoggedMessage {
-                        timestamp: header.timestamp,
-                        message: msg,
-                        is_armed: false,
-                        message_type: msg_type_str,
-                    });
-                }
-                Err(e) => {
-                    eprintln!("Failed to decode MAVLink message: {:?}", e);
                 }
+                messages.push(LoggedMessage {
+                    timestamp: header.timestamp,
+                    system_id: header.system_id,
+                    component_id: header.component_id,
+                    source: header.source,
+                    direction: header.direction,
+                    message,
+                    is_armed: false,
+                    message_type: msg_type_str,
+                });
             }
         }
 
         Ok(messages)
     }
-}
\ No newline at end of file
+}
+
+/// Treats a truncated last record as the end of the log, as the convenience readers
+/// and the replayers do: everything before it is intact.
+pub(crate) fn stop_at_truncation(next: Result<Option<BbinRecord>, BlackboxError>) -> Result<Option<BbinRecord>, BlackboxError> {
+    match next {
+        Err(BlackboxError::TruncatedRecord { offset }) => {
+            warn!(offset; "Log ends with a truncated record");
+            Ok(None)
+        }
+        next => next,
+    }
+}
+
+/// Builds the record for a logged header and the bytes that followed it, decoding
+/// message and statistics records again.
+pub(crate) fn decode_record(header: LoggedMessageHeader, bytes: Vec<u8>) -> Result<BbinRecord, BlackboxError> {
+    let record = match header.kind {
+        RecordKind::Message => {
+            match read_versioned_msg::<MavMessage, _>(&mut bytes.as_slice(), header.mavlink_version()) {
+                Ok((_hdr, message)) => BbinRecord::Message { header, message, bytes },
+                Err(_) => BbinRecord::Undecoded { header, reason: UndecodedReason::ParseError, bytes },
+            }
+        }
+        RecordKind::Undecoded(reason) => BbinRecord::Undecoded { header, reason, bytes },
+        RecordKind::Event(event) => BbinRecord::Event { header, event, bytes },
+        RecordKind::Stats => BbinRecord::Stats { header, stats: deserialize(&bytes)?, bytes },
+    };
+    Ok(record)
+}
+
+/// Record header of `BBIN_LEGACY_VERSION` files.
+#[derive(Deserialize)]
+struct LegacyRecordHeader {
+    timestamp: i64,
+    sequence: u8,
+    system_id: u8,
+    component_id: u8,
+    msg_len: u16,
+}
+
+/// Reads the header of the next record in a file of the given format version.
+pub(crate) fn read_record_header<R: Read>(reader: R, version: u16) -> bincode::Result<LoggedMessageHeader> {
+    if version != BBIN_LEGACY_VERSION {
+        return deserialize_from(reader);
+    }
+    let legacy: LegacyRecordHeader = deserialize_from(reader)?;
+    let header = MavHeader { sequence: legacy.sequence, system_id: legacy.system_id, component_id: legacy.component_id };
+    Ok(LoggedMessageHeader::from_mav_header(legacy.timestamp, header, MavlinkVersion::V2, legacy.msg_len as usize))
+}
+
+/// Reads and validates the BBIN file header at the start of `reader`.
+pub(crate) fn read_file_header<R: Read>(reader: &mut R) -> Result<BbinHeader, BlackboxError> {
+    // The rest of the header has changed between versions, so check the version
+    // before reading it.
+    let (magic, version): ([u8; 4], u16) = deserialize_from(&mut *reader)?;
+    if magic != *b"BBIN" {
+        return Err(BlackboxError::InvalidMagic(magic));
+    }
+    if version == BBIN_LEGACY_VERSION {
+        let start_timestamp = deserialize_from(reader)?;
+        return Ok(BbinHeader { magic, version, start_timestamp, previous_segment: None });
+    }
+    if !(BBIN_MIN_VERSION..=BBIN_VERSION).contains(&version) {
+        return Err(BlackboxError::UnsupportedVersion(version));
+    }
+    let (start_timestamp, previous_segment) = deserialize_from(reader)?;
+    Ok(BbinHeader { magic, version, start_timestamp, previous_segment })
+}
+
+/// Returns the offset where records end. A finalized file ends with the index,
+/// its offset and `BBIN_INDEX_MAGIC`; a file whose capture never finalized is all
+/// records. Leaves `file` positioned at the start.
+pub(crate) fn find_data_end(file: &mut File) -> Result<u64, BlackboxError> {
+    let file_len = file.seek(SeekFrom::End(0))?;
+    let mut data_end = file_len;
+    if let Some(trailer_pos) = file_len.checked_sub(TRAILER_LEN as u64) {
+        file.seek(SeekFrom::Start(trailer_pos))?;
+        let mut trailer = [0u8; TRAILER_LEN];
+        file.read_exact(&mut trailer)?;
+        data_end = data_end_from_trailer(&trailer, trailer_pos)?;
+    }
+    file.seek(SeekFrom::Start(0))?;
+    Ok(data_end)
+}
+
+/// Length of the index offset and `BBIN_INDEX_MAGIC` ending a finalized file.
+pub(crate) const TRAILER_LEN: usize = 12;
+
+/// Where records end, given the last `TRAILER_LEN` bytes of a file starting at
+/// `trailer_pos`. Without the index magic the whole file is records.
+pub(crate) fn data_end_from_trailer(trailer: &[u8; TRAILER_LEN], trailer_pos: u64) -> Result<u64, BlackboxError> {
+    if trailer[8..] != BBIN_INDEX_MAGIC {
+        return Ok(trailer_pos + TRAILER_LEN as u64);
+    }
+    let index_offset: u64 = deserialize(&trailer[..8])?;
+    Ok(index_offset.min(trailer_pos))
+}
+
+/// Where the records of a `BBIN_LEGACY_VERSION` file end. Those files were finalized
+/// with their index and its offset but no magic, so the offset is only trusted if a
+/// well-formed index fills the file from there up to it. Moves the file position.
+fn legacy_data_end(file: &mut File, records_start: u64) -> Result<u64, BlackboxError> {
+    let file_len = file.seek(SeekFrom::End(0))?;
+    let Some(footer_pos) = file_len.checked_sub(LEGACY_FOOTER_LEN as u64) else {
+        return Ok(file_len);
+    };
+    file.seek(SeekFrom::Start(footer_pos))?;
+    let mut footer = [0u8; LEGACY_FOOTER_LEN];
+    file.read_exact(&mut footer)?;
+    let Some(index_offset) = legacy_index_offset(&footer, records_start, footer_pos) else {
+        return Ok(file_len);
+    };
+    file.seek(SeekFrom::Start(index_offset))?;
+    let mut index = vec![0u8; (footer_pos - index_offset) as usize];
+    file.read_exact(&mut index)?;
+    Ok(if is_legacy_index(&index) { index_offset } else { file_len })
+}
+
+/// Length of the index offset ending a finalized `BBIN_LEGACY_VERSION` file.
+pub(crate) const LEGACY_FOOTER_LEN: usize = 8;
+
+/// The index offset a legacy footer at `footer_pos` names, if it lies between the
+/// first record and the footer.
+pub(crate) fn legacy_index_offset(footer: &[u8; LEGACY_FOOTER_LEN], records_start: u64, footer_pos: u64) -> Option<u64> {
+    let index_offset: u64 = deserialize(footer).ok()?;
+    (records_start..=footer_pos).contains(&index_offset).then_some(index_offset)
+}
+
+/// Whether `bytes` are exactly one legacy index.
+pub(crate) fn is_legacy_index(bytes: &[u8]) -> bool {
+    let mut rest = bytes;
+    deserialize_from::<_, Vec<BbinIndexEntry>>(&mut rest).is_ok() && rest.is_empty()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::bbin_writer::BbinWriter;
+    use crate::sink::MessageSink;
+    use crate::stats::TrafficStats;
+    use crate::types::{CaptureEvent, Direction};
+    use bincode::serialize;
+    use mavlink::ardupilotmega::HEARTBEAT_DATA;
+    use mavlink::{write_v1_msg, write_v2_msg};
+    use std::fs;
+    use std::path::PathBuf;
+
+    pub(crate) fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("blackboxer-{}-{}.bbin", name, std::process::id()))
+    }
+
+    pub(crate) fn heartbeat(sequence: u8) -> (MavHeader, Vec<u8>) {
+        let header = MavHeader { sequence, system_id: 1, component_id: 1 };
+        let mut bytes = Vec::new();
+        write_v2_msg(&mut bytes, header, &MavMessage::HEARTBEAT(HEARTBEAT_DATA::default())).unwrap();
+        (header, bytes)
+    }
+
+    /// Writes three heartbeats in the layout of blackboxer 0.1.4, with or without the
+    /// index it added when a capture ended cleanly.
+    pub(crate) fn write_legacy_file(path: &PathBuf, finalized: bool) {
+        let mut file = serialize(&(*b"BBIN", BBIN_LEGACY_VERSION, 1_000i64)).unwrap();
+        let mut index = Vec::new();
+        for sequence in 0..3 {
+            let (header, bytes) = heartbeat(sequence);
+            index.push(BbinIndexEntry { message_type: "MavMessage".to_string(), offset: file.len() as u64, timestamp: 1_000 + sequence as i64 });
+            let record_header = (1_000 + sequence as i64, header.sequence, header.system_id, header.component_id, bytes.len() as u16);
+            file.extend(serialize(&record_header).unwrap());
+            file.extend(bytes);
+        }
+        if finalized {
+            let index_offset = file.len() as u64;
+            file.extend(serialize(&index).unwrap());
+            file.extend(serialize(&index_offset).unwrap());
+        }
+        fs::write(path, file).unwrap();
+    }
+
+    #[test]
+    fn reads_legacy_files() {
+        for finalized in [false, true] {
+            let path = temp_path(&format!("legacy-{}", finalized));
+            write_legacy_file(&path, finalized);
+            let mut reader = BbinReader::new(path.to_str().unwrap()).unwrap();
+            assert_eq!(reader.file_header().version, BBIN_LEGACY_VERSION);
+            assert_eq!(reader.file_header().start_timestamp, 1_000);
+            assert_eq!(reader.file_header().previous_segment, None);
+            for sequence in 0..3 {
+                let Some(BbinRecord::Message { header, message, bytes }) = reader.next_record().unwrap() else {
+                    panic!("expected a message record");
+                };
+                assert_eq!(header.timestamp, 1_000 + sequence as i64);
+                assert_eq!((header.sequence, header.system_id, header.source, header.direction), (sequence, 1, 0, Direction::Downlink));
+                assert_eq!(header.mavlink_version(), MavlinkVersion::V2);
+                assert!(matches!(message, MavMessage::HEARTBEAT(_)));
+                assert_eq!(bytes, heartbeat(sequence).1);
+            }
+            assert!(reader.next_record().unwrap().is_none());
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn writer_reader_round_trip() {
+        let path = temp_path("round-trip");
+        let mut writer = BbinWriter::new_segment(path.to_str().unwrap(), Some("earlier.bbin")).unwrap();
+        let (header, v2_bytes) = heartbeat(0);
+        writer.write_message_raw(1, 0, Direction::Downlink, header, &v2_bytes).unwrap();
+        let mut v1_bytes = Vec::new();
+        write_v1_msg(&mut v1_bytes, header, &MavMessage::HEARTBEAT(HEARTBEAT_DATA::default())).unwrap();
+        writer.write_message_raw(2, 1, Direction::Uplink, header, &v1_bytes).unwrap();
+        writer.write_undecoded_raw(3, 0, Direction::Downlink, header, UndecodedReason::CrcMismatch, &[0xFD, 0x01, 0x02]).unwrap();
+        writer.write_event(4, 2, Direction::Uplink, CaptureEvent::SourceConnected, "127.0.0.1:5760").unwrap();
+        let stats = TrafficStats { frames: 3, bytes: 42, ..Default::default() };
+        let stats_bytes = serialize(&stats).unwrap();
+        let no_header = MavHeader { system_id: 0, component_id: 0, sequence: 0 };
+        let stats_header = LoggedMessageHeader::new(5, 0, Direction::Downlink, no_header, RecordKind::Stats, &stats_bytes);
+        writer.write_record(&BbinRecord::Stats { header: stats_header, stats, bytes: stats_bytes }).unwrap();
+        assert_eq!(writer.frames_written(), 3);
+        writer.finalize().unwrap();
+
+        let mut reader = BbinReader::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(reader.file_header().version, BBIN_VERSION);
+        assert_eq!(reader.file_header().previous_segment.as_deref(), Some("earlier.bbin"));
+        let records: Vec<BbinRecord> = std::iter::from_fn(|| reader.next_record().unwrap()).collect();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 5);
+
+        let BbinRecord::Message { header, bytes, .. } = &records[0] else { panic!("expected a v2 message") };
+        assert_eq!((header.timestamp, header.mavlink_version(), bytes), (1, MavlinkVersion::V2, &v2_bytes));
+        let BbinRecord::Message { header, bytes, .. } = &records[1] else { panic!("expected a v1 message") };
+        assert_eq!((header.source, header.direction, header.mavlink_version()), (1, Direction::Uplink, MavlinkVersion::V1));
+        assert_eq!(bytes, &v1_bytes);
+        let BbinRecord::Undecoded { reason, bytes, .. } = &records[2] else { panic!("expected an undecoded frame") };
+        assert_eq!((*reason, bytes.as_slice()), (UndecodedReason::CrcMismatch, &[0xFD, 0x01, 0x02][..]));
+        let BbinRecord::Event { header, event, bytes } = &records[3] else { panic!("expected an event") };
+        assert_eq!((header.source, *event, bytes.as_slice()), (2, CaptureEvent::SourceConnected, &b"127.0.0.1:5760"[..]));
+        let BbinRecord::Stats { stats, .. } = &records[4] else { panic!("expected statistics") };
+        assert_eq!((stats.frames, stats.bytes), (3, 42));
+    }
+}
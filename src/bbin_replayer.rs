@@ -1,104 +1,116 @@
-use crate::types::{BbinHeader, LoggedMessageHeader};
-use mavlink::ardupilotmega::MavMessage;
-use mavlink::{read_v2_msg, write_v2_msg, MavHeader};
-use std::fs::File;
-use std::io::{self, BufReader, Read, Write};
-use std::net::TcpStream;
-use std::time::{Duration, Instant};
-use bincode::deserialize_from;
+use crate::bbin_reader::{stop_at_truncation, BbinReader};
+use crate::connection::{Connection, Endpoint};
+use crate::error::BlackboxError;
+use crate::types::{BbinRecord, Direction};
+use mavlink::write_versioned_msg;
+use mavlink::MavHeader;
+use log::{info, trace};
+use std::io::Write;
+use std::time::Duration;
 
 pub struct BbinReplayer {
-    reader: BufReader<File>,
-    stream: TcpStream,
+    reader: BbinReader,
+    connection: Connection,
+    target: String,
+    verbatim: bool,
 }
 
 impl BbinReplayer {
-    pub fn new(file_path: &str, target: &str) -> io::Result<Self> {
-        let file = File::open(file_path)?;
-        let mut reader = BufReader::new(file);
-        let header: BbinHeader = deserialize_from(&mut reader)
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        if header.magic != *b"BBIN" {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid BBIN file magic"));
-        }
-        let stream = TcpStream::connect(target)?;
-        println!("Connected to {}", target);
-        Ok(BbinReplayer { reader, stream })
+    /// Opens `file_path` for replay towards `target`, a connection string such as
+    /// `tcpout:127.0.0.1:5760` or `udpout:127.0.0.1:14550`.
+    pub fn new(file_path: &str, target: &str) -> Result<Self, BlackboxError> {
+        let reader = BbinReader::new(file_path)?;
+        let endpoint: Endpoint = target.parse()?;
+        let connection = Connection::open(&endpoint)
+            .map_err(|source| BlackboxError::Link { endpoint: endpoint.to_string(), source })?;
+        info!("Connected to {}", endpoint);
+        Ok(BbinReplayer { reader, connection, target: endpoint.to_string(), verbatim: true })
+    }
+
+    /// Chooses between sending each logged frame byte-for-byte (the default) and
+    /// re-encoding it from the decoded message and logged header, which drops any
+    /// signature and recomputes the CRC.
+    pub fn set_verbatim(&mut self, verbatim: bool) {
+        self.verbatim = verbatim;
     }
 
-    pub fn replay_messages(&mut self, filter_msg_type: Option<&str>, realtime: bool, speed: f32) -> io::Result<()> {
+    /// Replays the logged downlink frames. Undecoded frames are sent as-is in verbatim
+    /// mode and skipped otherwise, or whenever a message type filter is given.
+    pub fn replay_messages(&mut self, filter_msg_type: Option<&str>, realtime: bool, speed: f32) -> Result<(), BlackboxError> {
         if speed <= 0.0 {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Speed must be a positive value"));
+            return Err(BlackboxError::InvalidConfig("Speed must be a positive value".to_string()));
         }
 
         let mut prev_time: Option<i64> = None;
-        let _start = Instant::now();
 
-        loop {
-            let header: LoggedMessageHeader = match deserialize_from(&mut self.reader) {
-                Ok(h) => h,
-                Err(e) => {
-                    match *e {
-                        bincode::ErrorKind::Io(ref io_err) if io_err.kind() == io::ErrorKind::UnexpectedEof => {
-                            println!("Reached end of file or encountered error.");
-                            break;
-                        }
-                        _ => {
-                            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
-                        }
-                    }
-                }
+        while let Some(record) = stop_at_truncation(self.reader.next_record())? {
+            let Some(frame) = frame_to_send(&record, filter_msg_type, self.verbatim)? else {
+                continue;
             };
-
-            let mut msg_buf = vec![0u8; header.msg_len as usize];
-            if let Err(e) = self.reader.read_exact(&mut msg_buf) {
-                eprintln!("Failed to read message bytes: {}", e);
-                break;
+            if realtime {
+                if let Some(delay) = replay_delay(prev_time, record.header().timestamp, speed) {
+                    std::thread::sleep(delay);
+                }
+                prev_time = Some(record.header().timestamp);
             }
+            self.connection.write_all(&frame).map_err(|source| BlackboxError::Link { endpoint: self.target.clone(), source })?;
+            report_replayed(&record);
+        }
 
-            let mut packet = &msg_buf[..];
-            match read_v2_msg::<MavMessage, _>(&mut packet) {
-                Ok((_, msg)) => {
-                    let msg_type = format!("{:?}", msg);
-                    if let Some(filter) = filter_msg_type {
-                        if !msg_type.contains(filter) {
-                            continue;
-                        }
-                    }
-
-                    if realtime {
-                        if let Some(prev) = prev_time {
-                            let delta = header.timestamp - prev;
-                            if delta > 0 {
-                                let adjusted_delta = (delta as f64 / speed as f64) as u64;
-                                std::thread::sleep(Duration::from_millis(adjusted_delta));
-                            }
-                        }
-                        prev_time = Some(header.timestamp);
-                    }
-
-                    let mut out_buf = Vec::new();
-                    let fake_header = MavHeader {
-                        sequence: header.sequence,
-                        system_id: header.system_id,
-                        component_id: header.component_id,
-                    };
-
-                    if let Err(e) = write_v2_msg(&mut out_buf, fake_header, &msg) {
-                        eprintln!("Failed to write message to buffer: {}", e);
-                        continue;
-                    }
+        info!("Replay complete");
+        Ok(())
+    }
+}
 
-                    self.stream.write_all(&out_buf)?;
-                    println!("Replayed message: {:?}", msg);
-                }
-                Err(e) => {
-                    eprintln!("Failed to decode message: {:?}", e);
+/// The bytes replay sends for `record`, or `None` if it is skipped. Fails if a
+/// message cannot be re-encoded.
+///
+/// Uplink records are what a GCS sent through the proxy, not vehicle traffic, and
+/// events and statistics describe the capture, so none of them are sent. Undecoded
+/// frames are sent as-is in verbatim mode and skipped otherwise, or whenever a
+/// message type filter is given.
+pub(crate) fn frame_to_send(record: &BbinRecord, filter_msg_type: Option<&str>, verbatim: bool) -> Result<Option<Vec<u8>>, BlackboxError> {
+    if record.header().direction == Direction::Uplink {
+        return Ok(None);
+    }
+    match record {
+        BbinRecord::Message { header, message, bytes } => {
+            if let Some(filter) = filter_msg_type {
+                if !format!("{:?}", message).contains(filter) {
+                    return Ok(None);
                 }
             }
+            if verbatim {
+                return Ok(Some(bytes.clone()));
+            }
+            let fake_header = MavHeader {
+                sequence: header.sequence,
+                system_id: header.system_id,
+                component_id: header.component_id,
+            };
+            // Send the frame in the MAVLink version it was captured in.
+            let mut out_buf = Vec::new();
+            write_versioned_msg(&mut out_buf, header.mavlink_version(), fake_header, message)?;
+            Ok(Some(out_buf))
         }
+        BbinRecord::Undecoded { bytes, .. } if filter_msg_type.is_none() && verbatim => Ok(Some(bytes.clone())),
+        BbinRecord::Undecoded { .. } | BbinRecord::Event { .. } | BbinRecord::Stats { .. } => Ok(None),
+    }
+}
 
-        println!("Replay complete.");
-        Ok(())
+/// How long to wait before sending a record logged at `timestamp` when the previous
+/// one was logged at `prev`, played back at `speed` times real time.
+pub(crate) fn replay_delay(prev: Option<i64>, timestamp: i64, speed: f32) -> Option<Duration> {
+    let delta = timestamp - prev?;
+    (delta > 0).then(|| Duration::from_millis((delta as f64 / speed as f64) as u64))
+}
+
+pub(crate) fn report_replayed(record: &BbinRecord) {
+    let header = record.header();
+    match record {
+        BbinRecord::Message { message, .. } => {
+            trace!(timestamp = header.timestamp, sysid = header.system_id, compid = header.component_id; "Replayed {:?}", message)
+        }
+        _ => trace!(timestamp = header.timestamp; "Replayed undecoded frame {:02X?}", record.bytes()),
     }
-}
\ No newline at end of file
+}
@@ -1,4 +1,6 @@
-use crate::types::{BbinHeader, BbinIndexEntry, LoggedMessageHeader};
+use crate::error::BlackboxError;
+use crate::sink::MessageSink;
+use crate::types::{BbinHeader, BbinIndexEntry, BbinRecord, CaptureEvent, Direction, LoggedMessageHeader, RecordKind, UndecodedReason, BBIN_INDEX_MAGIC, BBIN_VERSION};
 use mavlink::MavHeader;
 use std::fs::File;
 use std::io::{self, Write};
@@ -9,52 +11,106 @@ pub struct BbinWriter {
     file: File,
     index: Vec<BbinIndexEntry>,
     current_offset: u64,
+    frames_written: u64,
 }
 
 impl BbinWriter {
-    pub fn new(filename: &str) -> io::Result<Self> {
+    pub fn new(filename: &str) -> Result<Self, BlackboxError> {
+        Self::new_segment(filename, None)
+    }
+
+    /// Creates a file that continues `previous_segment`, the file name of the log
+    /// that was rotated out, and records that link in the header.
+    pub fn new_segment(filename: &str, previous_segment: Option<&str>) -> Result<Self, BlackboxError> {
         let mut file = File::create(filename)?;
         let header = BbinHeader {
             magic: *b"BBIN",
-            version: 10, // 1.0
+            version: BBIN_VERSION,
             start_timestamp: Utc::now().timestamp_millis(),
+            previous_segment: previous_segment.map(str::to_string),
         };
-        let header_bytes = serialize(&header).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let header_bytes = serialize(&header)?;
         file.write_all(&header_bytes)?;
         Ok(BbinWriter {
             file,
             index: Vec::new(),
             current_offset: header_bytes.len() as u64,
+            frames_written: 0,
         })
     }
 
-    pub fn write_message_raw(&mut self, timestamp: i64, header: MavHeader, raw_msg_bytes: &[u8]) -> io::Result<()> {
-        let logged_header = LoggedMessageHeader::from_mav_header(timestamp, header, raw_msg_bytes.len());
-        let header_bytes = serialize(&logged_header).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    /// Bytes written so far, not counting the index added by `finalize`.
+    pub fn bytes_written(&self) -> u64 {
+        self.current_offset
+    }
+
+    /// Message and undecoded records written so far; events and statistics are not counted.
+    pub fn frames_written(&self) -> u64 {
+        self.frames_written
+    }
+
+    /// Appends one MAVLink frame received on link `source`, travelling in `direction`.
+    /// The protocol version is taken from the frame's STX marker.
+    pub fn write_message_raw(&mut self, timestamp: i64, source: u8, direction: Direction, header: MavHeader, raw_msg_bytes: &[u8]) -> Result<(), BlackboxError> {
+        self.write_record(timestamp, source, direction, header, RecordKind::Message, raw_msg_bytes)
+    }
+
+    /// Appends a frame that could not be decoded, keeping its bytes and the reason.
+    pub fn write_undecoded_raw(&mut self, timestamp: i64, source: u8, direction: Direction, header: MavHeader, reason: UndecodedReason, raw_msg_bytes: &[u8]) -> Result<(), BlackboxError> {
+        self.write_record(timestamp, source, direction, header, RecordKind::Undecoded(reason), raw_msg_bytes)
+    }
+
+    /// Appends a capture event concerning link `source`, with a free-form detail string.
+    pub fn write_event(&mut self, timestamp: i64, source: u8, direction: Direction, event: CaptureEvent, detail: &str) -> Result<(), BlackboxError> {
+        self.write_record(timestamp, source, direction, MavHeader { system_id: 0, component_id: 0, sequence: 0 }, RecordKind::Event(event), detail.as_bytes())
+    }
+
+    fn write_record(&mut self, timestamp: i64, source: u8, direction: Direction, header: MavHeader, kind: RecordKind, raw_msg_bytes: &[u8]) -> Result<(), BlackboxError> {
+        self.append(&LoggedMessageHeader::new(timestamp, source, direction, header, kind, raw_msg_bytes), raw_msg_bytes)
+    }
+
+    fn append(&mut self, logged_header: &LoggedMessageHeader, raw_msg_bytes: &[u8]) -> Result<(), BlackboxError> {
+        let header_bytes = serialize(logged_header)?;
         self.file.write_all(&header_bytes)?;
         self.file.write_all(raw_msg_bytes)?;
         let msg_type = "MavMessage"; // Improve by extracting exact message type string if needed
         self.index.push(BbinIndexEntry {
             message_type: msg_type.to_string(),
             offset: self.current_offset,
-            timestamp,
+            timestamp: logged_header.timestamp,
         });
         self.current_offset += (header_bytes.len() + raw_msg_bytes.len()) as u64;
+        if matches!(logged_header.kind, RecordKind::Message | RecordKind::Undecoded(_)) {
+            self.frames_written += 1;
+        }
         Ok(())
     }
 
-    pub fn save_index(&mut self) -> io::Result<()> {
-        let index_bytes = serialize(&self.index).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    pub fn save_index(&mut self) -> Result<(), BlackboxError> {
+        let index_bytes = serialize(&self.index)?;
         self.file.write_all(&index_bytes)?;
         let footer = self.current_offset;
-        let footer_bytes = serialize(&footer).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let footer_bytes = serialize(&footer)?;
         self.file.write_all(&footer_bytes)?;
+        self.file.write_all(&BBIN_INDEX_MAGIC)?;
         Ok(())
     }
 
-    pub fn finalize(&mut self) -> io::Result<()> {
+    pub fn finalize(&mut self) -> Result<(), BlackboxError> {
         self.save_index()?;
         self.file.flush()?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Writes every record to a file of its own, e.g. a second unrotated copy of the log.
+/// The index is added when the capture ends.
+impl MessageSink for BbinWriter {
+    fn write_record(&mut self, record: &BbinRecord) -> io::Result<()> {
+        Ok(self.append(record.header(), record.bytes())?)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(self.finalize()?)
+    }
+}
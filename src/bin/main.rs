@@ -1,44 +1,158 @@
-use blackboxer::{BlackBoxer, BlackBoxerConfig, LoggedMessage};
-use mavlink::Message;
+use blackboxer::{BlackboxError, BlackBoxer, BlackBoxerConfig, CaptureFilter, ChannelSink, Connection, Endpoint, GcsEmulation, RateRequest, ReconnectPolicy, RotationPolicy, SinkErrorPolicy, UiOverflow};
 use std::env;
-use std::sync::{mpsc, Arc, atomic::{AtomicBool, Ordering}};
+use std::sync::Arc;
 use std::thread;
-use std::io::{self, Read};
+use std::time::Duration;
+use std::io;
+use std::path::PathBuf;
 
-fn main() -> std::io::Result<()> {
+fn main() -> Result<(), BlackboxError> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     let args: Vec<String> = env::args().collect();
     let armed_only = args.contains(&"--armed-only".to_string());
-    let addr = args.get(1).map_or("127.0.0.1:14552".to_string(), |s| s.clone());
+    let mut addrs: Vec<String> = args.iter().skip(1).filter(|a| !a.starts_with("--")).cloned().collect();
+    if addrs.is_empty() {
+        addrs.push("tcpout:127.0.0.1:14552".to_string());
+    }
+    let gcs_addrs: Vec<String> = args.iter()
+        .filter_map(|a| a.strip_prefix("--gcs="))
+        .map(str::to_string)
+        .collect();
+
+    let pre_arm_secs: u64 = args.iter()
+        .find(|a| a.starts_with("--pre-arm="))
+        .and_then(|s| s.trim_start_matches("--pre-arm=").parse().ok())
+        .unwrap_or(0);
+    let linger_secs: u64 = args.iter()
+        .find(|a| a.starts_with("--linger="))
+        .and_then(|s| s.trim_start_matches("--linger=").parse().ok())
+        .unwrap_or(0);
+    let dedup_millis: u64 = args.iter()
+        .find(|a| a.starts_with("--dedup="))
+        .and_then(|s| s.trim_start_matches("--dedup=").parse().ok())
+        .unwrap_or(0);
+
+    let reconnect = args.contains(&"--reconnect".to_string()).then(|| ReconnectPolicy {
+        max_retries: args.iter()
+            .find(|a| a.starts_with("--max-retries="))
+            .and_then(|s| s.trim_start_matches("--max-retries=").parse().ok()),
+        ..Default::default()
+    });
+
+    let gcs_emulation = match args.iter().find(|a| *a == "--emulate-gcs" || a.starts_with("--emulate-gcs=")) {
+        Some(arg) => {
+            let mut gcs = GcsEmulation::default();
+            if let Some((sysid, compid)) = arg.strip_prefix("--emulate-gcs=").and_then(|ids| ids.split_once(':')) {
+                gcs.system_id = sysid.parse().map_err(|_| BlackboxError::InvalidConfig("Invalid GCS system id".to_string()))?;
+                gcs.component_id = compid.parse().map_err(|_| BlackboxError::InvalidConfig("Invalid GCS component id".to_string()))?;
+            }
+            let rates = args.iter()
+                .filter_map(|a| a.strip_prefix("--rate="))
+                .map(|rate| rate.parse())
+                .collect::<Result<Vec<RateRequest>, _>>()?;
+            if !rates.is_empty() {
+                gcs.rates = rates;
+            }
+            Some(gcs)
+        }
+        None => None,
+    };
+
+    let rotation = RotationPolicy {
+        max_bytes: args.iter()
+            .find(|a| a.starts_with("--rotate-mb="))
+            .and_then(|s| s.trim_start_matches("--rotate-mb=").parse::<u64>().ok())
+            .map(|mb| mb * 1024 * 1024),
+        max_duration: args.iter()
+            .find(|a| a.starts_with("--rotate-mins="))
+            .and_then(|s| s.trim_start_matches("--rotate-mins=").parse::<u64>().ok())
+            .map(|mins| Duration::from_secs(mins * 60)),
+        per_arm_cycle: args.contains(&"--rotate-per-arm".to_string()),
+    };
+
+    let filter = CaptureFilter {
+        allow: args.iter().filter_map(|a| a.strip_prefix("--allow=")).map(str::parse).collect::<Result<_, _>>()?,
+        deny: args.iter().filter_map(|a| a.strip_prefix("--deny=")).map(str::parse).collect::<Result<_, _>>()?,
+        max_rates: args.iter().filter_map(|a| a.strip_prefix("--max-rate=")).map(str::parse).collect::<Result<_, _>>()?,
+    };
+
+    let defaults = BlackBoxerConfig::default();
+    let output_dir = args.iter()
+        .find_map(|a| a.strip_prefix("--out-dir="))
+        .map_or(defaults.output_dir, PathBuf::from);
+    let file_template = args.iter()
+        .find_map(|a| a.strip_prefix("--name="))
+        .map_or(defaults.file_template, str::to_string);
+    let stats_interval = match args.iter().find_map(|a| a.strip_prefix("--stats-interval=")) {
+        Some(secs) => {
+            let secs: u64 = secs.parse().map_err(|_| BlackboxError::InvalidConfig("Invalid stats interval".to_string()))?;
+            (secs > 0).then(|| Duration::from_secs(secs))
+        }
+        None => defaults.stats_interval,
+    };
 
     let config = BlackBoxerConfig {
         armed_only,
-        addr,
+        addrs,
+        gcs_addrs,
+        pre_arm_window: Duration::from_secs(pre_arm_secs),
+        post_disarm_linger: Duration::from_secs(linger_secs),
+        reconnect,
+        dedup_window: Duration::from_millis(dedup_millis),
+        gcs_emulation,
+        write_log: !args.contains(&"--no-log".to_string()),
+        rotation,
+        output_dir,
+        file_template,
+        stats_interval,
+        filter,
     };
 
-    let (tx, rx) = mpsc::channel::<LoggedMessage>();
-    let stop_flag = Arc::new(AtomicBool::new(false));
-    let stop_flag_clone = stop_flag.clone();
+    let ui_capacity: usize = args.iter()
+        .find(|a| a.starts_with("--ui-queue="))
+        .and_then(|s| s.trim_start_matches("--ui-queue=").parse().ok())
+        .unwrap_or(1000);
+    let ui_overflow: UiOverflow = match args.iter().find_map(|a| a.strip_prefix("--ui-overflow=")) {
+        Some(overflow) => overflow.parse()?,
+        None => UiOverflow::DropOldest,
+    };
 
-    // Spawn the capture in a background thread
-    thread::spawn(move || {
-        let mut blackboxer = BlackBoxer::new(config).unwrap();
-        blackboxer.capture_messages(tx, stop_flag_clone).unwrap();
-    });
+    let mut blackboxer = BlackBoxer::new(config)?;
+    let (ui_sink, ui_rx) = ChannelSink::bounded(ui_capacity, ui_overflow);
+    let ui_rx = Arc::new(ui_rx);
+    blackboxer.add_sink(ui_sink, SinkErrorPolicy::Remove);
+    for addr in args.iter().filter_map(|a| a.strip_prefix("--forward=")) {
+        let endpoint: Endpoint = addr.parse()?;
+        println!("Forwarding to {}", endpoint);
+        let connection = Connection::open(&endpoint)
+            .map_err(|source| BlackboxError::Link { endpoint: endpoint.to_string(), source })?;
+        blackboxer.add_sink(connection, SinkErrorPolicy::Ignore);
+    }
+    let capture = blackboxer.start()?;
 
     // Simulate UI: print messages
+    let rx = ui_rx.clone();
     thread::spawn(move || {
-        while let Ok(msg) = rx.recv() {
-            println!("[UI] {} @ {} | Armed: {} | Type: {}", msg.message_type, msg.timestamp, msg.is_armed, msg.message_type);
+        while let Some(msg) = rx.recv() {
+            println!("[UI] {} @ {} | Link: {} ({}) | Vehicle: {} | Armed: {} | Type: {}", msg.message_type, msg.timestamp, msg.source, msg.direction, msg.system_id, msg.is_armed, msg.message_type);
         }
     });
 
-    println!("Press Enter to stop...");
-    let _ = io::stdin().read(&mut [0u8]).unwrap();
-    stop_flag.store(true, Ordering::Relaxed);
+    println!("Commands: p = pause, r = resume, s = stats, Enter = stop");
+    for line in io::stdin().lines() {
+        match line?.trim() {
+            "p" => capture.pause(),
+            "r" => capture.resume(),
+            "s" => println!("{:#?}\n{:#?}", capture.stats(), ui_rx.stats()),
+            _ => break,
+        }
+    }
 
-    // Wait a bit to ensure clean shutdown
-    thread::sleep(std::time::Duration::from_secs(2));
-    println!("Stopped capture.");
+    let summary = capture.stop()?;
+    println!("Stopped capture after {:.1} s, {} frames written", summary.duration.as_secs_f64(), summary.stats.frames_written);
+    for path in &summary.files {
+        println!("Wrote {}", path.display());
+    }
 
     Ok(())
 }
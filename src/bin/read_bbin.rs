@@ -1,7 +1,8 @@
 use std::env;
-use blackboxer::BbinReader;
+use blackboxer::{BbinReader, BlackboxError};
 
-fn main() -> std::io::Result<()> {
+fn main() -> Result<(), BlackboxError> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     let args: Vec<String> = env::args().collect();
 
     if args.len() < 2 {
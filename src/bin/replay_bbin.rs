@@ -1,11 +1,12 @@
 use std::env;
-use blackboxer::BbinReplayer;
+use blackboxer::{BbinReplayer, BlackboxError};
 
-fn main() -> std::io::Result<()> {
+fn main() -> Result<(), BlackboxError> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
     let args: Vec<String> = env::args().collect();
 
     if args.len() < 3 {
-        eprintln!("Usage: {} <file.bbin> <tcp_target> [--filter=MSG_TYPE] [--realtime] [--speed=VALUE]", args[0]);
+        eprintln!("Usage: {} <file.bbin> <connection_string> [--filter=MSG_TYPE] [--realtime] [--speed=VALUE] [--reencode]", args[0]);
         return Ok(());
     }
 
@@ -19,8 +20,10 @@ fn main() -> std::io::Result<()> {
         .map(|s| s.trim_start_matches("--speed="))
         .and_then(|s| s.parse().ok())
         .unwrap_or(1.0);
+    let reencode = args.contains(&"--reencode".to_string());
 
     let mut replayer = BbinReplayer::new(file_path, target)?;
+    replayer.set_verbatim(!reencode);
     replayer.replay_messages(filter, realtime, speed)?;
 
     Ok(())
@@ -1,128 +1,1230 @@
 use crate::bbin_writer::BbinWriter;
-use crate::types::LoggedMessage;
-use mavlink::{read_v2_msg, write_v2_msg, ardupilotmega::MavMessage};
-use std::io::{self, Read, BufReader};
-use std::net::TcpStream;
+use crate::connection::{Connection, Endpoint};
+use crate::error::BlackboxError;
+use crate::filter::{CaptureFilter, FrameFilter};
+use crate::framer::{FramerStats, MavFramer, RawFrame};
+use crate::types::{BbinRecord, CaptureEvent, Direction, LoggedMessage, LoggedMessageHeader, RecordKind, UndecodedReason};
+use crate::gcs::{gcs_heartbeat, GcsEmulation, GcsEmulator};
+use crate::session::{CaptureHandle, CaptureStats};
+use crate::stats::{TrafficStats, TrafficTracker};
+use crate::sink::{MessageSink, SinkErrorPolicy};
+use mavlink::{read_versioned_msg, write_versioned_msg, ardupilotmega::MavMessage, MavHeader, MavlinkVersion, Message};
+use mavlink::ardupilotmega::{MavAutopilot, MavModeFlag};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
 use chrono::Utc;
-use std::sync::mpsc::Sender;
-use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use log::{debug, error, info, trace, warn};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+use std::thread::{self, JoinHandle};
+
+/// How long link readers and listeners block before checking whether the capture has
+/// ended, and the longest the capture loop waits for input when no timer is due.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long the first log file waits for an autopilot heartbeat when its name uses
+/// `{sysid}`, `{autopilot}` or `{flight}`, before it is named without one.
+const VEHICLE_WAIT: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
 pub struct BlackBoxerConfig {
     pub armed_only: bool,
-    pub addr: String,
+    /// Connection strings such as `tcpout:127.0.0.1:5760` or `udpin:0.0.0.0:14550`.
+    /// All of them are read at once and logged into the same file. A `udpout` link
+    /// sends one GCS heartbeat whenever it opens, so a remote that only answers
+    /// clients it has heard from starts sending; that heartbeat is not logged.
+    pub addrs: Vec<String>,
+    /// Proxy mode: connection strings for the GCS side, e.g. `udpin:0.0.0.0:14550` or
+    /// `tcpin:0.0.0.0:5760`. Frames from `addrs` are forwarded to these links and
+    /// frames from these links to `addrs`, and both directions are logged.
+    pub gcs_addrs: Vec<String>,
+    /// With `armed_only`, how much traffic from before arming is kept in memory and
+    /// written to the log once a vehicle arms.
+    pub pre_arm_window: Duration,
+    /// With `armed_only`, how long logging continues after the last vehicle disarms.
+    pub post_disarm_linger: Duration,
+    /// Reconnect after a link fails instead of dropping it. The capture ends once
+    /// no link is left.
+    pub reconnect: Option<ReconnectPolicy>,
+    /// Drop a frame if the same bytes arrived on another link within this long,
+    /// e.g. when a vehicle talks over both a radio and an LTE link. Zero disables it.
+    pub dedup_window: Duration,
+    /// Send heartbeats and stream rate requests to the vehicle links as a GCS would.
+    /// What is sent is logged as uplink traffic.
+    pub gcs_emulation: Option<GcsEmulation>,
+    /// Write the built-in rotating BBIN log. Without it the sinks added with
+    /// `BlackBoxer::add_sink` are the only output, and `rotation`, `output_dir` and
+    /// `file_template` go unused.
+    pub write_log: bool,
+    /// When to close the log file and continue in a new one.
+    pub rotation: RotationPolicy,
+    /// Directory the log files are written to; created if missing.
+    pub output_dir: PathBuf,
+    /// Log file name without the `.bbin` extension. Placeholders:
+    ///
+    ///  * `{date}` when the file was started, as `%Y%m%d_%H%M%S`
+    ///  * `{sysid}` system id of the first autopilot seen, `unknown` before that
+    ///  * `{autopilot}` its autopilot type, e.g. `ardupilotmega` or `px4`
+    ///  * `{flight}` number of arm cycles seen so far in this capture, from 0
+    ///
+    /// With any placeholder but `{date}`, the first file waits up to 5 s for an
+    /// autopilot heartbeat before it is created. A `_001`, `_002`, ... suffix is added
+    /// when the name is already taken.
+    pub file_template: String,
+    /// How often a `TrafficStats` snapshot is written to the log, and once more when
+    /// the capture ends. `None` writes none; the live stats are kept either way.
+    pub stats_interval: Option<Duration>,
+    /// Which received frames are logged, and how often each message type at most.
+    pub filter: CaptureFilter,
+}
+
+/// Limits that start a new log file. Each finished segment is finalized with its
+/// index, and the next segment names it in its header. A segment is only rotated
+/// once it holds at least one frame; zero limits are rejected by `BlackBoxer::new`.
+#[derive(Debug, Clone, Default)]
+pub struct RotationPolicy {
+    /// Rotate once a segment has grown to this many bytes.
+    pub max_bytes: Option<u64>,
+    /// Rotate once a segment has been written to for this long.
+    pub max_duration: Option<Duration>,
+    /// Rotate whenever a vehicle arms after all were disarmed, so each arm cycle
+    /// starts a file of its own.
+    pub per_arm_cycle: bool,
+}
+
+/// How `BlackBoxer` retries a failed link. The delay between attempts starts at
+/// `initial_backoff` and doubles up to `max_backoff`.
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Give up after this many failed attempts in a row; `None` retries forever.
+    pub max_retries: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            max_retries: None,
+        }
+    }
+}
+
+impl Default for BlackBoxerConfig {
+    fn default() -> Self {
+        BlackBoxerConfig {
+            armed_only: false,
+            addrs: vec!["tcpout:127.0.0.1:14552".to_string()],
+            gcs_addrs: Vec::new(),
+            pre_arm_window: Duration::ZERO,
+            post_disarm_linger: Duration::ZERO,
+            reconnect: None,
+            dedup_window: Duration::ZERO,
+            gcs_emulation: None,
+            write_log: true,
+            rotation: RotationPolicy::default(),
+            output_dir: PathBuf::from("."),
+            file_template: "mavlink_log_{date}".to_string(),
+            stats_interval: Some(Duration::from_secs(10)),
+            filter: CaptureFilter::default(),
+        }
+    }
 }
 
 pub struct BlackBoxer {
-    stream: TcpStream,
-    is_armed: bool,
+    /// Bound for `tcpin` endpoints; every accepted connection becomes a link
+    /// carrying traffic in the listener's direction.
+    listeners: Vec<(TcpListener, Direction)>,
+    links: Vec<Link>,
+    /// Source id handed to the next accepted connection.
+    next_source: u8,
+    /// Framer counters of links that have since closed.
+    closed_stats: FramerStats,
+    dedup: Option<Deduplicator>,
+    filter: FrameFilter,
+    gcs: Option<GcsEmulator>,
+    /// Arm state per system id, fed only by autopilot heartbeats.
+    armed: HashMap<u8, bool>,
+    /// Frames seen while not recording, at most `pre_arm_window` old.
+    pre_arm: VecDeque<BbinRecord>,
+    /// End of the post-disarm linger period, in milliseconds since the UNIX epoch.
+    linger_until: Option<i64>,
+    /// System id and autopilot type of the first autopilot seen, for file names.
+    first_vehicle: Option<(u8, MavAutopilot)>,
+    /// Arm cycles seen so far in this capture.
+    flights: u32,
+    /// Segment of the built-in log being written; `None` outside `capture_messages`
+    /// and without `write_log`.
+    log: Option<BbinWriter>,
+    /// Set while the first segment waits to be named after the vehicle, until when at
+    /// most. Records logged meanwhile wait in `unwritten`.
+    log_opens_at: Option<Instant>,
+    unwritten: Vec<BbinRecord>,
+    /// File name of the segment being written, relative to `output_dir`.
+    segment_file: String,
+    segment_started: Instant,
+    /// Every file written by the current capture, in order.
+    produced: Vec<PathBuf>,
+    /// Records and bytes written to segments that have been rotated out.
+    earlier_frames: u64,
+    earlier_bytes: u64,
+    /// Set through `CaptureHandle::pause`; nothing is logged while it is.
+    paused: Arc<AtomicBool>,
+    was_paused: bool,
+    stats: Arc<Mutex<CaptureStats>>,
+    traffic: TrafficTracker,
+    next_stats_record: Instant,
+    /// Registered with `add_sink`; every record logged is passed to each of them.
+    sinks: Vec<(Box<dyn MessageSink>, SinkErrorPolicy)>,
+    /// Errors returned by sinks so far.
+    sink_errors: u64,
+    /// Fed by the reader and listener threads, and by `CaptureHandle` to wake the loop.
+    inputs: Receiver<LinkInput>,
+    input_tx: Sender<LinkInput>,
+    /// Tells the reader and listener threads of the current capture to exit.
+    readers_stop: Arc<AtomicBool>,
+    readers: Vec<JoinHandle<()>>,
     config: BlackBoxerConfig,
 }
 
+/// What the reader and listener threads hand to the capture loop.
+pub(crate) enum LinkInput {
+    /// Bytes read from link `source`, stamped the moment the read returned.
+    Data { source: u8, timestamp: i64, bytes: Vec<u8> },
+    /// The link failed or its peer went away; its reader has exited.
+    Closed { source: u8, detail: String },
+    Accepted { stream: TcpStream, peer: SocketAddr, direction: Direction },
+    /// Outcome of a reconnect attempt made off the capture thread for link `source`.
+    Reconnected { source: u8, result: io::Result<Connection> },
+    /// Sent by `CaptureHandle` so a stop or pause is seen right away.
+    Wake,
+}
+
+/// A connection being captured from, with its own framing state.
+struct Link {
+    /// Written to every record from this link.
+    source: u8,
+    /// `Downlink` for vehicle-side links, `Uplink` for GCS-side links in proxy mode.
+    direction: Direction,
+    /// Peer address for accepted connections, the endpoint otherwise.
+    name: String,
+    /// Where to reconnect to; `None` for accepted connections, which are dropped when they close.
+    endpoint: Option<Endpoint>,
+    state: LinkState,
+    framer: MavFramer,
+    /// Version of the last valid frame received, used for frames we send on the link.
+    version: MavlinkVersion,
+}
+
+enum LinkState {
+    Up(Connection),
+    /// Waiting to reconnect under the `ReconnectPolicy`.
+    Down { retry_at: Instant, backoff: Duration, failures: u32 },
+    /// A reconnect attempt is running on its own thread, since opening a link can
+    /// block for as long as the OS takes to give up on an unreachable host.
+    Connecting { backoff: Duration, failures: u32 },
+}
+
 impl BlackBoxer {
-    pub fn new(config: BlackBoxerConfig) -> io::Result<Self> {
-        println!("Connecting to {}", config.addr);
-        let stream = TcpStream::connect(&config.addr)?;
-        stream.set_nonblocking(true)?;
-        println!("TCP connection established with {}", config.addr);
+    /// Opens every link in `config.addrs`, then `config.gcs_addrs`. Dialled links get
+    /// source ids in the order they are listed; `tcpin` endpoints only bind here and
+    /// their connections are numbered after them as they are accepted.
+    pub fn new(config: BlackBoxerConfig) -> Result<Self, BlackboxError> {
+        if config.addrs.is_empty() {
+            return Err(BlackboxError::InvalidConfig("No endpoints to capture from".to_string()));
+        }
+        let unknown_placeholder = render_template(&config.file_template, "", "", "", "").contains(['{', '}']);
+        if config.file_template.is_empty() || unknown_placeholder {
+            return Err(BlackboxError::InvalidConfig(format!("Invalid file template: {}", config.file_template)));
+        }
+        let rotation = &config.rotation;
+        if rotation.max_bytes == Some(0) || rotation.max_duration.is_some_and(|max| max.is_zero()) {
+            return Err(BlackboxError::InvalidConfig("Rotation limits must be greater than zero".to_string()));
+        }
+        if config.write_log {
+            fs::create_dir_all(&config.output_dir)?;
+        }
+        let mut listeners = Vec::new();
+        let mut links = Vec::new();
+        let vehicle_side = config.addrs.iter().map(|addr| (addr, Direction::Downlink));
+        let gcs_side = config.gcs_addrs.iter().map(|addr| (addr, Direction::Uplink));
+        for (addr, direction) in vehicle_side.chain(gcs_side) {
+            let endpoint: Endpoint = addr.parse()?;
+            if let Endpoint::TcpIn(addr) = &endpoint {
+                let listen = || -> io::Result<TcpListener> {
+                    let listener = TcpListener::bind(addr)?;
+                    listener.set_nonblocking(true)?;
+                    Ok(listener)
+                };
+                let listener = listen().map_err(|source| BlackboxError::Link { endpoint: endpoint.to_string(), source })?;
+                info!(direction:% = direction; "Listening on {}", listener.local_addr()?);
+                listeners.push((listener, direction));
+                continue;
+            }
+            debug!("Connecting to {}", endpoint);
+            let connection = Connection::open(&endpoint)
+                .map_err(|source| BlackboxError::Link { endpoint: endpoint.to_string(), source })?;
+            info!(source = links.len(), direction:% = direction; "Connected to {}", endpoint);
+            links.push(Link {
+                source: links.len() as u8,
+                direction,
+                name: endpoint.to_string(),
+                endpoint: Some(endpoint),
+                state: LinkState::Up(connection),
+                framer: MavFramer::new(),
+                version: MavlinkVersion::V2,
+            });
+        }
+        let dedup = (!config.dedup_window.is_zero()).then(|| Deduplicator::new(config.dedup_window));
+        let gcs = config.gcs_emulation.clone().map(GcsEmulator::new);
+        let (input_tx, inputs) = mpsc::channel();
         Ok(BlackBoxer {
-            stream,
-            is_armed: false,
+            listeners,
+            next_source: links.len() as u8,
+            links,
+            closed_stats: FramerStats::default(),
+            dedup,
+            filter: FrameFilter::new(config.filter.clone()),
+            gcs,
+            armed: HashMap::new(),
+            pre_arm: VecDeque::new(),
+            linger_until: None,
+            first_vehicle: None,
+            flights: 0,
+            log: None,
+            log_opens_at: None,
+            unwritten: Vec::new(),
+            segment_file: String::new(),
+            segment_started: Instant::now(),
+            produced: Vec::new(),
+            earlier_frames: 0,
+            earlier_bytes: 0,
+            paused: Arc::new(AtomicBool::new(false)),
+            was_paused: false,
+            stats: Arc::new(Mutex::new(CaptureStats::default())),
+            traffic: TrafficTracker::new(),
+            next_stats_record: Instant::now(),
+            sinks: Vec::new(),
+            sink_errors: 0,
+            inputs,
+            input_tx,
+            readers_stop: Arc::new(AtomicBool::new(false)),
+            readers: Vec::new(),
             config,
         })
     }
 
-    /// Captures MAVLink messages and pushes them to the UI in real time.
+    /// Passes every record logged from now on to `sink` as well, e.g. a `ChannelSink`
+    /// feeding a UI. `on_error` decides what a failing sink does to the capture.
+    pub fn add_sink(&mut self, sink: impl MessageSink + 'static, on_error: SinkErrorPolicy) {
+        self.sinks.push((Box::new(sink), on_error));
+    }
+
+    /// Runs the capture on a new thread and returns a handle to pause, inspect and stop it.
+    pub fn start(mut self) -> Result<CaptureHandle, BlackboxError> {
+        let stop_flag = Arc::new(AtomicBool::new(false));
+        let paused = self.paused.clone();
+        let stats = self.stats.clone();
+        let wake = self.input_tx.clone();
+        let thread_stop_flag = stop_flag.clone();
+        let thread = thread::Builder::new()
+            .name("blackboxer-capture".to_string())
+            .spawn(move || self.capture_messages(thread_stop_flag))?;
+        Ok(CaptureHandle { thread: Some(thread), stop_flag, paused, stats, wake, started: Instant::now() })
+    }
+
+    /// Address of the first `tcpin` listener, e.g. to find the port picked for `:0`.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listeners.first().and_then(|(listener, _)| listener.local_addr().ok())
+    }
+
+    /// Captures MAVLink messages into the log and every sink in real time. Returns the
+    /// paths of the log files written, more than one if the log was rotated.
+    ///
+    /// Each link is read on a thread of its own, so frames are timestamped as they
+    /// arrive. A stop requested through the flag alone is seen within 100 ms.
     ///
     /// # Arguments
-    /// * `ui_tx` - Sender for pushing messages to the UI
     /// * `stop_flag` - Arc<AtomicBool> flag to signal stopping the capture loop
-    pub fn capture_messages(&mut self, ui_tx: Sender<LoggedMessage>, stop_flag: Arc<AtomicBool>) -> io::Result<()> {
-        let mut reader = BufReader::new(&self.stream);
-        let mut buf = [0u8; 512];
-        let mut bbin_writer = BbinWriter::new(&format!("mavlink_log_{}.bbin", Utc::now().format("%Y%m%d_%H%M%S")))?;
+    pub fn capture_messages(&mut self, stop_flag: Arc<AtomicBool>) -> Result<Vec<PathBuf>, BlackboxError> {
+        // Anything left over from an earlier capture is stale.
+        while self.inputs.try_recv().is_ok() {}
+        self.readers_stop.store(false, Ordering::Relaxed);
+        let result = self.start_readers().and_then(|()| self.run(&stop_flag));
+        self.readers_stop.store(true, Ordering::Relaxed);
+        for reader in self.readers.drain(..) {
+            let _ = reader.join();
+        }
+        result
+    }
+
+    fn run(&mut self, stop_flag: &AtomicBool) -> Result<Vec<PathBuf>, BlackboxError> {
+        self.produced.clear();
+        self.flights = 0;
+        self.earlier_frames = 0;
+        self.earlier_bytes = 0;
+        self.traffic = TrafficTracker::new();
+        self.next_stats_record = Instant::now() + self.config.stats_interval.unwrap_or_default();
+        self.unwritten.clear();
+        let template = &self.config.file_template;
+        if !self.config.write_log {
+            debug!("Built-in log disabled, records only go to the sinks");
+        } else if ["{sysid}", "{autopilot}", "{flight}"].iter().any(|placeholder| template.contains(placeholder)) {
+            self.log_opens_at = Some(Instant::now() + VEHICLE_WAIT);
+        } else {
+            self.open_first_segment()?;
+        }
 
-        println!("Monitoring for arm/disarm events...");
+        debug!("Monitoring for arm/disarm events");
 
         loop {
             // Check stop flag at the start of each loop
             if stop_flag.load(Ordering::Relaxed) {
-                println!("Stop flag set. Exiting capture loop.");
+                info!("Capture stopped");
+                break;
+            }
+            if self.log_opens_at.is_some_and(|at| Instant::now() >= at) {
+                debug!("No autopilot heartbeat yet, naming the log without one");
+                self.open_first_segment()?;
+            }
+            self.update_paused()?;
+            self.retry_links();
+            self.send_gcs_traffic()?;
+            self.update_traffic()?;
+            if let Some(reason) = self.rotation_due() {
+                self.rotate(reason)?;
+            }
+            if self.links.is_empty() && self.listeners.is_empty() {
+                warn!("No links left to capture from, ending capture");
                 break;
             }
-            match reader.read(&mut buf) {
-                Ok(amt) if amt > 0 => {
-                    let mut packet = &buf[..amt];
-                    while !packet.is_empty() {
-                        match read_v2_msg::<MavMessage, &[u8]>(&mut packet) {
-                            Ok((header, msg)) => {
-                                let timestamp = Utc::now();
-
-                                match &msg {
-                                    MavMessage::HEARTBEAT(heartbeat) => {
-                                        let new_armed = heartbeat.system_status
-                                            == mavlink::ardupilotmega::MavState::MAV_STATE_ACTIVE;
-                                        if new_armed != self.is_armed {
-                                            self.is_armed = new_armed;
-                                            println!("Vehicle {}armed", if new_armed { "" } else { "dis" });
-                                            
-                                            // Send arm state change to UI
-                                            let _ = ui_tx.send(LoggedMessage {
-                                                timestamp: timestamp.timestamp_millis(),
-                                                message: msg.clone(),
-                                                is_armed: new_armed,
-                                                message_type: "ARM_STATE".to_string(),
-                                            });
-                                        }
-                                    }
-                                    _ => {}
-                                }
-
-                                if !self.config.armed_only || self.is_armed {
-                                    let mut raw_msg_bytes = Vec::new();
-                                    write_v2_msg(&mut raw_msg_bytes, header, &msg)
-                                        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-
-                                    bbin_writer.write_message_raw(
-                                        timestamp.timestamp_millis(),
-                                        header,
-                                        &raw_msg_bytes,
-                                    )?;
-
-                                    // Send message to UI with enhanced information
-                                    let logged_msg = LoggedMessage {
-                                        timestamp: timestamp.timestamp_millis(),
-                                        message: msg.clone(),
-                                        is_armed: self.is_armed,
-                                        message_type: format!("{:?}", msg),
-                                    };
-
-                                    let _ = ui_tx.send(logged_msg);
-                                    println!("Captured message: {:?}", msg);
-                                }
-                            }
-                            Err(e) => {
-                                eprintln!("Failed to parse MAVLink message: {:?}", e);
-                                break;
-                            }
+            self.publish_stats();
+
+            match self.inputs.recv_timeout(self.next_wakeup()) {
+                Ok(input) => self.handle_input(input)?,
+                Err(RecvTimeoutError::Timeout) => {}
+                // Cannot happen while we hold `input_tx`.
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        if self.config.stats_interval.is_some() {
+            self.log_traffic()?;
+        }
+        if self.log_opens_at.is_some() {
+            self.open_first_segment()?;
+        }
+        self.publish_traffic();
+        let finished = self.finish_sinks();
+        if let Some(log) = &mut self.log {
+            log.finalize()?;
+        }
+        self.publish_stats();
+        self.log = None;
+        finished?;
+        Ok(std::mem::take(&mut self.produced))
+    }
+
+    /// Starts a reader for every connected link and an acceptor for every listener.
+    fn start_readers(&mut self) -> Result<(), BlackboxError> {
+        for link in &mut self.links {
+            if let LinkState::Up(connection) = &mut link.state {
+                let reader = spawn_reader(link.source, connection, self.input_tx.clone(), self.readers_stop.clone())?;
+                self.readers.push(reader);
+                if self.gcs.is_none() {
+                    announce(link);
+                }
+            }
+        }
+        for (listener, direction) in &self.listeners {
+            let acceptor = spawn_acceptor(listener, *direction, self.input_tx.clone(), self.readers_stop.clone())?;
+            self.readers.push(acceptor);
+        }
+        Ok(())
+    }
+
+    /// How long the loop may wait for input before a timer is due: the next GCS
+    /// message, link retry, duration rotation, traffic stats update or the first log
+    /// file giving up on a vehicle name, and at most
+    /// `POLL_INTERVAL`.
+    fn next_wakeup(&self) -> Duration {
+        let now = Instant::now();
+        let mut wakeup = now + POLL_INTERVAL;
+        if let Some(gcs) = &self.gcs {
+            wakeup = wakeup.min(gcs.next_due());
+        }
+        for link in &self.links {
+            if let LinkState::Down { retry_at, .. } = link.state {
+                wakeup = wakeup.min(retry_at);
+            }
+        }
+        if let Some(max) = self.config.rotation.max_duration {
+            // An empty segment is not rotated, so its deadline is no reason to wake.
+            if self.log.as_ref().is_some_and(|log| log.frames_written() > 0) {
+                wakeup = wakeup.min(self.segment_started + max);
+            }
+        }
+        if let Some(at) = self.log_opens_at {
+            wakeup = wakeup.min(at);
+        }
+        wakeup = wakeup.min(self.traffic.next_roll());
+        if self.config.stats_interval.is_some() {
+            wakeup = wakeup.min(self.next_stats_record);
+        }
+        wakeup.saturating_duration_since(now)
+    }
+
+    fn handle_input(&mut self, input: LinkInput) -> Result<(), BlackboxError> {
+        match input {
+            LinkInput::Data { source, timestamp, bytes } => {
+                let Some(i) = self.links.iter().position(|link| link.source == source) else {
+                    return Ok(());
+                };
+                let direction = self.links[i].direction;
+                self.links[i].framer.push(&bytes);
+                while let Some(frame) = self.links[i].framer.next_frame() {
+                    if frame.defect.is_none() {
+                        self.links[i].version = frame.version;
+                    }
+                    self.handle_frame(source, direction, timestamp, &frame)?;
+                }
+            }
+            LinkInput::Closed { source, detail } => {
+                if let Some(i) = self.links.iter().position(|link| link.source == source) {
+                    self.link_failed(i, &detail)?;
+                }
+            }
+            LinkInput::Accepted { stream, peer, direction } => self.accept_connection(stream, peer, direction)?,
+            LinkInput::Reconnected { source, result } => self.reconnected(source, result)?,
+            LinkInput::Wake => {}
+        }
+        Ok(())
+    }
+
+    /// Creates the file for the current segment and maps the source id of every
+    /// link to its name, so each segment can be read on its own.
+    fn open_segment(&mut self, previous: Option<&str>) -> Result<BbinWriter, BlackboxError> {
+        let (sysid, autopilot) = match self.first_vehicle {
+            Some((sysid, autopilot)) => (sysid.to_string(), autopilot_name(autopilot)),
+            None => ("unknown".to_string(), "unknown".to_string()),
+        };
+        let date = Utc::now().format("%Y%m%d_%H%M%S").to_string();
+        let base = render_template(&self.config.file_template, &date, &sysid, &autopilot, &self.flights.to_string());
+
+        // Never overwrite an earlier log, e.g. when rotating twice within a second.
+        let mut name = format!("{}.bbin", base);
+        let mut suffix = 0;
+        while self.config.output_dir.join(&name).exists() {
+            suffix += 1;
+            name = format!("{}_{:03}.bbin", base, suffix);
+        }
+        let path = self.config.output_dir.join(&name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        self.segment_file = name;
+        self.segment_started = Instant::now();
+        let mut bbin_writer = BbinWriter::new_segment(&path.to_string_lossy(), previous)?;
+        info!(file:% = path.display(); "Logging to {}", path.display());
+        self.produced.push(path);
+        for link in &self.links {
+            bbin_writer.write_event(Utc::now().timestamp_millis(), link.source, link.direction, CaptureEvent::SourceConnected, &link.name)?;
+        }
+        Ok(bbin_writer)
+    }
+
+    /// Opens the first segment and writes what was logged while it waited.
+    fn open_first_segment(&mut self) -> Result<(), BlackboxError> {
+        self.log_opens_at = None;
+        let mut log = self.open_segment(None)?;
+        for record in std::mem::take(&mut self.unwritten) {
+            log.write_record(&record)?;
+        }
+        self.log = Some(log);
+        Ok(())
+    }
+
+    /// Finalizes the current segment and carries on in the next one.
+    fn rotate(&mut self, reason: &str) -> Result<(), BlackboxError> {
+        let previous = std::mem::take(&mut self.segment_file);
+        let next = self.open_segment(Some(&previous))?;
+        let Some(mut finished) = self.log.replace(next) else {
+            return Ok(());
+        };
+        finished.finalize()?;
+        self.earlier_frames += finished.frames_written();
+        self.earlier_bytes += finished.bytes_written();
+        info!(reason; "Rotated log: {} continues in {}", previous, self.segment_file);
+        Ok(())
+    }
+
+    /// Logs a pause or resume requested through the `CaptureHandle`.
+    fn update_paused(&mut self) -> Result<(), BlackboxError> {
+        let paused = self.paused.load(Ordering::Relaxed);
+        if paused != self.was_paused {
+            self.was_paused = paused;
+            let event = if paused { CaptureEvent::Paused } else { CaptureEvent::Resumed };
+            info!("Recording {}", event);
+            // Not tied to a link, so logged under source 0.
+            self.emit(&event_record(0, Direction::Downlink, event, ""))?;
+        }
+        Ok(())
+    }
+
+    fn publish_stats(&self) {
+        let mut snapshot = CaptureStats {
+            framer: self.framer_stats(),
+            frames_written: self.earlier_frames + self.log.as_ref().map_or(0, BbinWriter::frames_written),
+            bytes_written: self.earlier_bytes + self.log.as_ref().map_or(0, BbinWriter::bytes_written),
+            duplicates_dropped: self.duplicates_dropped(),
+            frames_filtered: self.filter.filtered,
+            frames_decimated: self.filter.decimated,
+            links_up: self.links.iter().filter(|link| matches!(link.state, LinkState::Up(_))).count(),
+            current_file: self.produced.last().cloned(),
+            paused: self.was_paused,
+            sinks: self.sinks.len(),
+            sink_errors: self.sink_errors,
+            traffic: TrafficStats::default(),
+        };
+        if let Ok(mut stats) = self.stats.lock() {
+            // Published with its rates by `publish_traffic` instead, once per window.
+            snapshot.traffic = std::mem::take(&mut stats.traffic);
+            *stats = snapshot;
+        }
+    }
+
+    fn publish_traffic(&self) {
+        if let Ok(mut stats) = self.stats.lock() {
+            stats.traffic = self.traffic.stats().clone();
+        }
+    }
+
+    /// Recomputes the traffic rates and logs a statistics record when they are due.
+    fn update_traffic(&mut self) -> Result<(), BlackboxError> {
+        let now = Instant::now();
+        if self.traffic.roll(now) {
+            self.publish_traffic();
+        }
+        if let Some(interval) = self.config.stats_interval {
+            if now >= self.next_stats_record {
+                self.next_stats_record = now + interval;
+                self.log_traffic()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the current `TrafficStats` to the log and the sinks as a statistics record.
+    /// Record lengths are 16 bits, so a snapshot of many busy components is split over
+    /// several records with the same timestamp, each with the totals.
+    fn log_traffic(&mut self) -> Result<(), BlackboxError> {
+        let timestamp = Utc::now().timestamp_millis();
+        for stats in self.traffic.stats().split(u16::MAX as u64)? {
+            let bytes = bincode::serialize(&stats)?;
+            if bytes.len() > u16::MAX as usize {
+                warn!(bytes = bytes.len(); "Statistics of a single component too large to log");
+                continue;
+            }
+            let no_header = MavHeader { system_id: 0, component_id: 0, sequence: 0 };
+            let header = LoggedMessageHeader::new(timestamp, 0, Direction::Downlink, no_header, RecordKind::Stats, &bytes);
+            self.record(BbinRecord::Stats { header, stats, bytes })?;
+        }
+        Ok(())
+    }
+
+    /// Whether the size or duration limit of the current segment has been reached.
+    /// A segment with no frames yet is never rotated, however small the limits.
+    fn rotation_due(&self) -> Option<&'static str> {
+        // Nothing to rotate while the first segment waits to be opened.
+        let log = self.log.as_ref()?;
+        if log.frames_written() == 0 {
+            return None;
+        }
+        let bytes_written = log.bytes_written();
+        let rotation = &self.config.rotation;
+        if rotation.max_bytes.is_some_and(|max| bytes_written >= max) {
+            return Some("reaching the size limit");
+        }
+        if rotation.max_duration.is_some_and(|max| self.segment_started.elapsed() >= max) {
+            return Some("reaching the duration limit");
+        }
+        None
+    }
+
+    /// Turns a connection accepted on a `tcpin` listener into a link with a new source id.
+    fn accept_connection(&mut self, stream: TcpStream, peer: SocketAddr, direction: Direction) -> Result<(), BlackboxError> {
+        // Some platforms hand out accepted sockets in the listener's non-blocking mode.
+        stream.set_nonblocking(false)?;
+        // Ids of closed connections come round again once the ids wrap, but one a
+        // link still holds never does.
+        let free = (0..=u8::MAX)
+            .map(|offset| self.next_source.wrapping_add(offset))
+            .find(|&id| self.links.iter().all(|link| link.source != id));
+        let Some(source) = free else {
+            warn!(peer:% = peer, direction:% = direction; "Refusing connection from {}: all source ids are in use", peer);
+            return Ok(());
+        };
+        self.next_source = source.wrapping_add(1);
+        let mut connection = Connection::Tcp(stream);
+        info!(source, peer:% = peer, direction:% = direction; "Accepted connection from {}", peer);
+        self.emit(&event_record(source, direction, CaptureEvent::SourceConnected, &peer.to_string()))?;
+        self.readers.retain(|reader| !reader.is_finished());
+        self.readers.push(spawn_reader(source, &mut connection, self.input_tx.clone(), self.readers_stop.clone())?);
+        self.links.push(Link {
+            source,
+            direction,
+            name: peer.to_string(),
+            endpoint: None,
+            state: LinkState::Up(connection),
+            framer: MavFramer::new(),
+            version: MavlinkVersion::V2,
+        });
+        Ok(())
+    }
+
+    /// Records that the link at `index` failed. Dialled links wait to reconnect if a
+    /// policy is set; otherwise the link is removed.
+    fn link_failed(&mut self, index: usize, detail: &str) -> Result<(), BlackboxError> {
+        let link = &self.links[index];
+        let (source, direction) = (link.source, link.direction);
+        if link.endpoint.is_none() {
+            info!(source, peer = link.name.as_str(); "Connection from {} closed: {}", link.name, detail);
+            self.remove_link(index);
+            return self.emit(&event_record(source, direction, CaptureEvent::SourceDisconnected, detail));
+        }
+
+        warn!(source, endpoint = link.name.as_str(); "Link to {} lost: {}", link.name, detail);
+        self.emit(&event_record(source, direction, CaptureEvent::LinkLost, detail))?;
+        match &self.config.reconnect {
+            Some(policy) => {
+                self.links[index].state = LinkState::Down {
+                    retry_at: Instant::now() + policy.initial_backoff,
+                    backoff: policy.initial_backoff,
+                    failures: 0,
+                };
+            }
+            None => self.remove_link(index),
+        }
+        Ok(())
+    }
+
+    /// Starts a reconnect attempt for every link whose backoff has elapsed. The
+    /// outcome arrives as `LinkInput::Reconnected`.
+    fn retry_links(&mut self) {
+        if self.config.reconnect.is_none() {
+            return;
+        }
+        for link in &mut self.links {
+            let (LinkState::Down { retry_at, backoff, failures }, Some(endpoint)) = (&link.state, &link.endpoint) else {
+                continue;
+            };
+            if Instant::now() < *retry_at {
+                continue;
+            }
+            let (source, endpoint, tx) = (link.source, endpoint.clone(), self.input_tx.clone());
+            link.state = LinkState::Connecting { backoff: *backoff, failures: *failures };
+            thread::spawn(move || {
+                // Nobody is listening any more if the capture ended meanwhile.
+                let _ = tx.send(LinkInput::Reconnected { source, result: Connection::open(&endpoint) });
+            });
+        }
+    }
+
+    /// Puts a reconnected link back to work, or doubles its backoff after a failure
+    /// and drops it once `max_retries` is reached.
+    fn reconnected(&mut self, source: u8, result: io::Result<Connection>) -> Result<(), BlackboxError> {
+        let (Some(policy), Some(i)) = (&self.config.reconnect, self.links.iter().position(|link| link.source == source)) else {
+            return Ok(());
+        };
+        let link = &mut self.links[i];
+        let LinkState::Connecting { backoff, failures } = link.state else {
+            return Ok(());
+        };
+        let (tx, stop) = (self.input_tx.clone(), self.readers_stop.clone());
+        let reopened = result.and_then(|mut connection| {
+            let reader = spawn_reader(source, &mut connection, tx, stop)?;
+            Ok((connection, reader))
+        });
+        match reopened {
+            Ok((connection, reader)) => {
+                self.readers.retain(|reader| !reader.is_finished());
+                self.readers.push(reader);
+                link.state = LinkState::Up(connection);
+                link.framer.reset();
+                if self.gcs.is_none() {
+                    announce(link);
+                }
+                info!(source, endpoint = link.name.as_str(), failures; "Link to {} restored", link.name);
+                let detail = format!("after {} failed attempts", failures);
+                let record = event_record(link.source, link.direction, CaptureEvent::LinkRestored, &detail);
+                self.emit(&record)?;
+            }
+            Err(e) => {
+                let failures = failures + 1;
+                warn!(source, endpoint = link.name.as_str(), failures; "Reconnect attempt {} to {} failed: {}", failures, link.name, e);
+                if policy.max_retries.is_some_and(|max| failures >= max) {
+                    error!(source, endpoint = link.name.as_str(), failures; "Giving up on {} after {} attempts", link.name, failures);
+                    self.remove_link(i);
+                    return Ok(());
+                }
+                let backoff = (backoff * 2).min(policy.max_backoff);
+                link.state = LinkState::Down { retry_at: Instant::now() + backoff, backoff, failures };
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends whatever heartbeats and rate requests are due to every vehicle link and
+    /// logs each copy as uplink traffic on that link.
+    fn send_gcs_traffic(&mut self) -> Result<(), BlackboxError> {
+        let Some(gcs) = &mut self.gcs else {
+            return Ok(());
+        };
+        let due = gcs.due_messages(Instant::now());
+        let timestamp = Utc::now().timestamp_millis();
+        for (header, message) in due {
+            for i in 0..self.links.len() {
+                let link = &mut self.links[i];
+                let LinkState::Up(connection) = &mut link.state else {
+                    continue;
+                };
+                if link.direction != Direction::Downlink {
+                    continue;
+                }
+                let mut bytes = Vec::new();
+                write_versioned_msg(&mut bytes, link.version, header, &message)?;
+                match connection.send_frame(&bytes) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
+                        warn!(source = link.source, endpoint = link.name.as_str(); "Failed to send {} to {}: {}", message.message_name(), link.name, e);
+                        continue;
+                    }
+                }
+                let logged_header = LoggedMessageHeader::new(timestamp, link.source, Direction::Uplink, header, RecordKind::Message, &bytes);
+                self.record(BbinRecord::Message { header: logged_header, message: message.clone(), bytes })?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_link(&mut self, index: usize) {
+        let link = self.links.remove(index);
+        self.closed_stats += link.framer.stats();
+    }
+
+    /// Whether the vehicle with `system_id` last reported itself armed.
+    pub fn is_armed(&self, system_id: u8) -> bool {
+        self.armed.get(&system_id).copied().unwrap_or(false)
+    }
+
+    /// Whether any vehicle seen so far is armed.
+    pub fn any_armed(&self) -> bool {
+        self.armed.values().any(|&armed| armed)
+    }
+
+    /// Counters from the stream framers of all links, including ones that have closed,
+    /// with bytes discarded while resynchronising.
+    pub fn framer_stats(&self) -> FramerStats {
+        let mut stats = self.closed_stats;
+        for link in &self.links {
+            stats += link.framer.stats();
+        }
+        stats
+    }
+
+    /// Received traffic per (system id, component id), with rates over the last second.
+    pub fn traffic_stats(&self) -> &TrafficStats {
+        self.traffic.stats()
+    }
+
+    /// Frames dropped because the same bytes already arrived on another link.
+    pub fn duplicates_dropped(&self) -> u64 {
+        self.dedup.as_ref().map_or(0, |dedup| dedup.dropped)
+    }
+
+    fn handle_frame(&mut self, source: u8, direction: Direction, timestamp: i64, frame: &RawFrame) -> Result<(), BlackboxError> {
+        if self.dedup.as_mut().is_some_and(|dedup| dedup.is_duplicate(source, &frame.bytes, timestamp)) {
+            return Ok(());
+        }
+        self.forward(direction, frame);
+        let decoded = match frame.defect {
+            Some(reason) => Err(reason),
+            // The parser's error can be had again from `BbinRecord::decode`.
+            None => read_versioned_msg::<MavMessage, &[u8]>(&mut frame.bytes.as_slice(), frame.version)
+                .map_err(|_| UndecodedReason::ParseError),
+        };
+        self.traffic.frame(frame, decoded.as_ref().ok().map(|(_, msg)| msg));
+        let logged = self.filter.keep(frame, timestamp);
+        let (header, msg) = match decoded {
+            Ok(decoded) => decoded,
+            Err(_) if !logged => return Ok(()),
+            Err(reason) => {
+                // Keep what the vehicle sent even though it cannot be decoded.
+                let kind = RecordKind::Undecoded(reason);
+                let header = LoggedMessageHeader::new(timestamp, source, direction, frame.header(), kind, &frame.bytes);
+                self.record(BbinRecord::Undecoded { header, reason, bytes: frame.bytes.clone() })?;
+                return Ok(());
+            }
+        };
+
+        // GCSs, gimbals and companion computers also send heartbeats, but only an
+        // autopilot's base_mode says whether its vehicle is armed.
+        if let MavMessage::HEARTBEAT(heartbeat) = &msg {
+            if direction == Direction::Downlink && heartbeat.autopilot != MavAutopilot::MAV_AUTOPILOT_INVALID {
+                let any_was_armed = self.any_armed();
+                let new_armed = heartbeat.base_mode.contains(MavModeFlag::MAV_MODE_FLAG_SAFETY_ARMED);
+                let was_armed = self.armed.insert(header.system_id, new_armed).unwrap_or(false);
+                self.first_vehicle.get_or_insert((header.system_id, heartbeat.autopilot));
+                if let Some(gcs) = &mut self.gcs {
+                    gcs.autopilot_seen(header.system_id, header.component_id);
+                }
+                if new_armed != was_armed {
+                    info!(sysid = header.system_id, armed = new_armed; "Vehicle {} {}armed", header.system_id, if new_armed { "" } else { "dis" });
+
+                    let change = LoggedMessage {
+                        timestamp,
+                        system_id: header.system_id,
+                        component_id: header.component_id,
+                        source,
+                        direction,
+                        message: msg.clone(),
+                        is_armed: new_armed,
+                        message_type: "ARM_STATE".to_string(),
+                    };
+                    self.for_each_sink(|sink| sink.arm_state_changed(&change))?;
+                }
+
+                match (any_was_armed, self.any_armed()) {
+                    (false, true) => {
+                        self.flights += 1;
+                        // A segment with no traffic yet, e.g. in armed-only mode, needs no rotation.
+                        let frames_written = self.log.as_ref().map_or(0, BbinWriter::frames_written);
+                        if self.config.rotation.per_arm_cycle && frames_written > 0 {
+                            self.rotate("a new arm cycle")?;
                         }
+                        self.flush_pre_arm()?;
                     }
+                    (true, false) => {
+                        self.linger_until = Some(timestamp + self.config.post_disarm_linger.as_millis() as i64);
+                    }
+                    _ => {}
                 }
-                Ok(_) => {
-                    std::thread::sleep(std::time::Duration::from_millis(10));
+                if self.log_opens_at.is_some() {
+                    self.open_first_segment()?;
                 }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    std::thread::sleep(std::time::Duration::from_millis(10));
+            }
+        }
+
+        if !logged {
+            return Ok(());
+        }
+        // Log the frame exactly as received, keeping flags, signature and CRC intact.
+        let logged_header = LoggedMessageHeader::new(timestamp, source, direction, header, RecordKind::Message, &frame.bytes);
+        if self.record(BbinRecord::Message { header: logged_header, message: msg.clone(), bytes: frame.bytes.clone() })? {
+            trace!(source, sysid = header.system_id, compid = header.component_id, msgid = msg.message_id(); "Captured {:?}", msg);
+        }
+        Ok(())
+    }
+
+    /// In proxy mode, passes a frame on byte-for-byte to every link on the other side.
+    /// Unknown message ids are forwarded too; CRC mismatches are not, since they may
+    /// overlap a real frame that is forwarded on its own.
+    fn forward(&mut self, from: Direction, frame: &RawFrame) {
+        if frame.defect == Some(UndecodedReason::CrcMismatch) {
+            return;
+        }
+        for link in &mut self.links {
+            let LinkState::Up(connection) = &mut link.state else {
+                continue;
+            };
+            if link.direction == from {
+                continue;
+            }
+            if let Err(e) = connection.send_frame(&frame.bytes) {
+                warn!(source = link.source, endpoint = link.name.as_str(); "Failed to forward to {}: {}", link.name, e);
+            }
+        }
+    }
+
+    /// Whether frames arriving at `timestamp` go to the log rather than the pre-arm buffer.
+    fn is_recording(&self, timestamp: i64) -> bool {
+        !self.config.armed_only
+            || self.any_armed()
+            || self.linger_until.is_some_and(|until| timestamp < until)
+    }
+
+    /// Writes `record` to the log and the sinks while recording, otherwise keeps it in
+    /// the pre-arm buffer. Nothing is kept while paused. Returns whether it was written.
+    fn record(&mut self, record: BbinRecord) -> Result<bool, BlackboxError> {
+        if self.was_paused {
+            return Ok(false);
+        }
+        let timestamp = record.header().timestamp;
+        if self.is_recording(timestamp) {
+            self.emit(&record)?;
+            return Ok(true);
+        }
+
+        let window = self.config.pre_arm_window.as_millis() as i64;
+        if window > 0 {
+            let oldest = timestamp - window;
+            while self.pre_arm.front().is_some_and(|r| r.header().timestamp < oldest) {
+                self.pre_arm.pop_front();
+            }
+            self.pre_arm.push_back(record);
+        }
+        Ok(false)
+    }
+
+    fn flush_pre_arm(&mut self) -> Result<(), BlackboxError> {
+        if !self.pre_arm.is_empty() {
+            debug!(records = self.pre_arm.len(); "Flushing pre-arm buffer");
+        }
+        for record in std::mem::take(&mut self.pre_arm) {
+            self.emit(&record)?;
+        }
+        Ok(())
+    }
+
+    /// Writes `record` to the built-in log, then passes it to every sink.
+    fn emit(&mut self, record: &BbinRecord) -> Result<(), BlackboxError> {
+        match &mut self.log {
+            Some(log) => log.write_record(record)?,
+            None if self.log_opens_at.is_some() => self.unwritten.push(record.clone()),
+            None => {}
+        }
+        self.for_each_sink(|sink| sink.write_record(record))
+    }
+
+    /// Calls `f` on every sink and applies its `SinkErrorPolicy` to any error.
+    fn for_each_sink(&mut self, mut f: impl FnMut(&mut dyn MessageSink) -> io::Result<()>) -> Result<(), BlackboxError> {
+        let mut i = 0;
+        while i < self.sinks.len() {
+            let (sink, on_error) = &mut self.sinks[i];
+            if let Err(e) = f(sink.as_mut()) {
+                self.sink_errors += 1;
+                match on_error {
+                    SinkErrorPolicy::Abort => return Err(BlackboxError::Sink(e)),
+                    SinkErrorPolicy::Ignore => warn!(sink = i; "Sink failed: {}", e),
+                    SinkErrorPolicy::Remove => {
+                        error!(sink = i; "Sink failed and was removed: {}", e);
+                        let (mut sink, _) = self.sinks.remove(i);
+                        let _ = sink.finish();
+                        continue;
+                    }
+                }
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+
+    /// Finishes every sink at the end of a capture. All of them are finished even if
+    /// one fails; the first error from an `Abort` sink is returned.
+    fn finish_sinks(&mut self) -> Result<(), BlackboxError> {
+        let mut result = Ok(());
+        for (i, (mut sink, on_error)) in std::mem::take(&mut self.sinks).into_iter().enumerate() {
+            if let Err(e) = sink.finish() {
+                self.sink_errors += 1;
+                warn!(sink = i; "Sink failed to finish: {}", e);
+                if on_error == SinkErrorPolicy::Abort && result.is_ok() {
+                    result = Err(BlackboxError::Sink(e));
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Reads `connection` on a thread of its own until it fails or `stop` is set. Both
+/// `connection`, which stays with the link for writing, and the reader get
+/// `POLL_INTERVAL` timeouts, so neither blocks the capture for long.
+fn spawn_reader(source: u8, connection: &mut Connection, tx: Sender<LinkInput>, stop: Arc<AtomicBool>) -> io::Result<JoinHandle<()>> {
+    connection.set_timeout(Some(POLL_INTERVAL))?;
+    let mut reader = connection.try_clone()?;
+    reader.set_timeout(Some(POLL_INTERVAL))?;
+    thread::Builder::new().name(format!("blackboxer-link-{}", source)).spawn(move || {
+        // Large enough to hold a whole UDP datagram carrying several frames.
+        let mut buf = [0u8; 4096];
+        while !stop.load(Ordering::Relaxed) {
+            let input = match reader.read(&mut buf) {
+                Ok(amt) if amt > 0 => LinkInput::Data {
+                    source,
+                    timestamp: Utc::now().timestamp_millis(),
+                    bytes: buf[..amt].to_vec(),
+                },
+                Ok(_) if reader.is_stream() => LinkInput::Closed { source, detail: "connection closed by peer".to_string() },
+                Ok(_) => continue,
+                Err(ref e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted) => continue,
+                // An ICMP port unreachable for something we sent, e.g. before SITL or
+                // a router is listening. The socket is fine and the peer may yet come up.
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused && !reader.is_stream() => {
+                    trace!(source; "Datagram peer not listening: {}", e);
                     continue;
                 }
                 Err(e) => {
-                    eprintln!("TCP Read error: {:?}", e);
-                    break;
+                    debug!(source; "Read error: {}", e);
+                    LinkInput::Closed { source, detail: e.to_string() }
                 }
+            };
+            let closed = matches!(input, LinkInput::Closed { .. });
+            if tx.send(input).is_err() || closed {
+                break;
             }
         }
+    })
+}
 
-        bbin_writer.finalize()?;
-        Ok(())
+/// Sends a GCS heartbeat on a vehicle-side `udpout` link, so a router or autopilot
+/// that only answers clients it has heard from learns where to send. With
+/// `gcs_emulation` the emulator's own heartbeats do this instead. Nothing is logged.
+fn announce(link: &mut Link) {
+    let (LinkState::Up(connection), Some(Endpoint::UdpOut(_)), Direction::Downlink) = (&mut link.state, &link.endpoint, link.direction) else {
+        return;
+    };
+    let identity = GcsEmulation::default();
+    let header = MavHeader { system_id: identity.system_id, component_id: identity.component_id, sequence: 0 };
+    let mut bytes = Vec::new();
+    // Writing to a Vec cannot fail.
+    let _ = write_versioned_msg(&mut bytes, link.version, header, &gcs_heartbeat());
+    if let Err(e) = connection.send_frame(&bytes) {
+        warn!(source = link.source, endpoint = link.name.as_str(); "Failed to announce ourselves to {}: {}", link.name, e);
+    }
+}
+
+/// Accepts connections on `listener` until `stop` is set. std has no accept timeout,
+/// so the non-blocking listener is polled every `POLL_INTERVAL`; this only delays
+/// new connections, not the timestamps of their frames.
+fn spawn_acceptor(listener: &TcpListener, direction: Direction, tx: Sender<LinkInput>, stop: Arc<AtomicBool>) -> io::Result<JoinHandle<()>> {
+    let listener = listener.try_clone()?;
+    thread::Builder::new().name("blackboxer-accept".to_string()).spawn(move || {
+        while !stop.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    if tx.send(LinkInput::Accepted { stream, peer, direction }).is_err() {
+                        break;
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                Err(e) => {
+                    // A connection that failed before we got to it; the listener is still fine.
+                    warn!(direction:% = direction; "Accept error: {}", e);
+                    thread::sleep(POLL_INTERVAL);
+                }
+            }
+        }
+    })
+}
+
+/// A capture event concerning link `source`, stamped with the current time.
+fn event_record(source: u8, direction: Direction, event: CaptureEvent, detail: &str) -> BbinRecord {
+    let no_header = MavHeader { system_id: 0, component_id: 0, sequence: 0 };
+    let bytes = detail.as_bytes().to_vec();
+    let header = LoggedMessageHeader::new(Utc::now().timestamp_millis(), source, direction, no_header, RecordKind::Event(event), &bytes);
+    BbinRecord::Event { header, event, bytes }
+}
+
+/// Fills in the placeholders of a `file_template`.
+fn render_template(template: &str, date: &str, sysid: &str, autopilot: &str, flight: &str) -> String {
+    template
+        .replace("{date}", date)
+        .replace("{sysid}", sysid)
+        .replace("{autopilot}", autopilot)
+        .replace("{flight}", flight)
+}
+
+/// Short lower-case autopilot name, e.g. `ardupilotmega` for `MAV_AUTOPILOT_ARDUPILOTMEGA`.
+fn autopilot_name(autopilot: MavAutopilot) -> String {
+    let name = format!("{:?}", autopilot);
+    name.trim_start_matches("MAV_AUTOPILOT_").to_lowercase()
+}
+
+/// Remembers the frames seen within the last `window` milliseconds and which link
+/// delivered each one first.
+struct Deduplicator {
+    window: i64,
+    seen: HashMap<Vec<u8>, u8>,
+    order: VecDeque<(i64, Vec<u8>)>,
+    dropped: u64,
+}
+
+impl Deduplicator {
+    fn new(window: Duration) -> Self {
+        Deduplicator { window: window.as_millis() as i64, seen: HashMap::new(), order: VecDeque::new(), dropped: 0 }
+    }
+
+    /// Whether `bytes` is a copy of a frame another link delivered within the window.
+    /// Repeats on the same link are left alone.
+    fn is_duplicate(&mut self, source: u8, bytes: &[u8], timestamp: i64) -> bool {
+        let oldest = timestamp - self.window;
+        while self.order.front().is_some_and(|(seen_at, _)| *seen_at < oldest) {
+            if let Some((_, expired)) = self.order.pop_front() {
+                self.seen.remove(&expired);
+            }
+        }
+        match self.seen.get(bytes) {
+            Some(&first) if first != source => {
+                self.dropped += 1;
+                true
+            }
+            Some(_) => false,
+            None => {
+                self.seen.insert(bytes.to_vec(), source);
+                self.order.push_back((timestamp, bytes.to_vec()));
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_zero_rotation_limits() {
+        let zero_bytes = RotationPolicy { max_bytes: Some(0), ..Default::default() };
+        let zero_duration = RotationPolicy { max_duration: Some(Duration::ZERO), ..Default::default() };
+        for rotation in [zero_bytes, zero_duration] {
+            let config = BlackBoxerConfig { rotation, ..Default::default() };
+            assert!(matches!(BlackBoxer::new(config), Err(BlackboxError::InvalidConfig(_))));
+        }
+    }
+
+    #[test]
+    fn dedup_drops_copies_from_other_links() {
+        let mut dedup = Deduplicator::new(Duration::from_millis(100));
+        assert!(!dedup.is_duplicate(0, b"frame", 1_000));
+        assert!(dedup.is_duplicate(1, b"frame", 1_050));
+        assert!(dedup.is_duplicate(2, b"frame", 1_100));
+        assert!(!dedup.is_duplicate(1, b"other", 1_100));
+        assert_eq!(dedup.dropped, 2);
+    }
+
+    #[test]
+    fn dedup_keeps_repeats_on_the_same_link() {
+        let mut dedup = Deduplicator::new(Duration::from_millis(100));
+        assert!(!dedup.is_duplicate(0, b"frame", 1_000));
+        assert!(!dedup.is_duplicate(0, b"frame", 1_010));
+        assert!(dedup.is_duplicate(1, b"frame", 1_020));
+        assert_eq!(dedup.dropped, 1);
+    }
+
+    #[test]
+    fn dedup_forgets_frames_after_the_window() {
+        let mut dedup = Deduplicator::new(Duration::from_millis(100));
+        assert!(!dedup.is_duplicate(0, b"frame", 1_000));
+        // Past the window the copy is new, and the link it came on owns it now.
+        assert!(!dedup.is_duplicate(1, b"frame", 1_101));
+        assert!(dedup.is_duplicate(0, b"frame", 1_150));
+        assert_eq!((dedup.seen.len(), dedup.order.len()), (1, 1));
     }
 }
@@ -0,0 +1,292 @@
+use crate::error::BlackboxError;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use serialport::SerialPort;
+
+/// Read/write timeout used for serial ports in blocking mode.
+const SERIAL_BLOCKING_TIMEOUT: Duration = Duration::from_secs(u32::MAX as u64);
+
+/// A MAVLink endpoint parsed from a mavlink-style connection string.
+///
+/// Supported forms:
+///
+///  * `tcpout:<host>:<port>` dial out over TCP (a bare `<host>:<port>` means the same)
+///  * `tcpin:<addr>:<port>` listen for TCP connections; `BlackBoxer` accepts any
+///    number of them, `Connection::open` waits for the first
+///  * `udpin:<addr>:<port>` bind and receive datagrams from any sender
+///  * `udpout:<host>:<port>` exchange datagrams with a remote from an ephemeral port
+///  * `udpbcast:<addr>:<port>` broadcast datagrams and receive from any sender
+///  * `serial:<device>:<baud>` open a serial device, e.g. `serial:/dev/ttyUSB0:57600`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    TcpOut(String),
+    TcpIn(String),
+    UdpIn(String),
+    UdpOut(String),
+    UdpBcast(String),
+    Serial { path: String, baud_rate: u32 },
+}
+
+impl FromStr for Endpoint {
+    type Err = BlackboxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: &str| BlackboxError::InvalidConfig(format!("{}: {}", msg, s));
+
+        let Some((scheme, rest)) = s.split_once(':') else {
+            return Err(invalid("Connection string must be <scheme>:<address>"));
+        };
+        match scheme {
+            "tcpout" => Ok(Endpoint::TcpOut(rest.to_string())),
+            "tcpin" => Ok(Endpoint::TcpIn(rest.to_string())),
+            "udpin" => Ok(Endpoint::UdpIn(rest.to_string())),
+            "udpout" => Ok(Endpoint::UdpOut(rest.to_string())),
+            "udpbcast" => Ok(Endpoint::UdpBcast(rest.to_string())),
+            "serial" => {
+                let (path, baud) = rest.rsplit_once(':')
+                    .ok_or_else(|| invalid("Serial connection string must be serial:<device>:<baud>"))?;
+                let baud_rate = baud.parse().map_err(|_| invalid("Invalid serial baud rate"))?;
+                Ok(Endpoint::Serial { path: path.to_string(), baud_rate })
+            }
+            // Plain `host:port`, kept for compatibility with earlier addresses.
+            _ if s.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) => {
+                Ok(Endpoint::TcpOut(s.to_string()))
+            }
+            _ => Err(invalid("Unsupported connection scheme")),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::TcpOut(addr) => write!(f, "tcpout:{}", addr),
+            Endpoint::TcpIn(addr) => write!(f, "tcpin:{}", addr),
+            Endpoint::UdpIn(addr) => write!(f, "udpin:{}", addr),
+            Endpoint::UdpOut(addr) => write!(f, "udpout:{}", addr),
+            Endpoint::UdpBcast(addr) => write!(f, "udpbcast:{}", addr),
+            Endpoint::Serial { path, baud_rate } => write!(f, "serial:{}:{}", path, baud_rate),
+        }
+    }
+}
+
+/// An open link to a MAVLink endpoint.
+///
+/// Reads return whatever the underlying transport delivered: a chunk of the TCP
+/// byte stream or serial port, or one whole UDP datagram.
+pub enum Connection {
+    Tcp(TcpStream),
+    UdpIn {
+        socket: UdpSocket,
+        /// Last address a datagram was received from, used as the reply target.
+        /// Shared with clones, so a reader thread keeps the writer's target current.
+        peer: Arc<Mutex<Option<SocketAddr>>>,
+    },
+    UdpOut(UdpSocket),
+    UdpBcast {
+        socket: UdpSocket,
+        dest: SocketAddr,
+    },
+    Serial(Box<dyn SerialPort>),
+}
+
+impl Connection {
+    /// Opens `endpoint`. For `tcpin` this blocks until a peer connects.
+    pub fn open(endpoint: &Endpoint) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::TcpOut(addr) => Ok(Connection::Tcp(TcpStream::connect(addr)?)),
+            Endpoint::TcpIn(addr) => {
+                let listener = TcpListener::bind(addr)?;
+                let (stream, _) = listener.accept()?;
+                Ok(Connection::Tcp(stream))
+            }
+            Endpoint::UdpIn(addr) => Ok(Connection::UdpIn {
+                socket: UdpSocket::bind(addr)?,
+                peer: Arc::new(Mutex::new(None)),
+            }),
+            Endpoint::UdpOut(addr) => {
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.connect(addr)?;
+                Ok(Connection::UdpOut(socket))
+            }
+            Endpoint::UdpBcast(addr) => {
+                let dest = addr.to_socket_addrs()?.next()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Broadcast address did not resolve"))?;
+                let socket = UdpSocket::bind("0.0.0.0:0")?;
+                socket.set_broadcast(true)?;
+                Ok(Connection::UdpBcast { socket, dest })
+            }
+            Endpoint::Serial { path, baud_rate } => {
+                let port = serialport::new(path, *baud_rate)
+                    .timeout(SERIAL_BLOCKING_TIMEOUT)
+                    .open()
+                    .map_err(io::Error::from)?;
+                Ok(Connection::Serial(port))
+            }
+        }
+    }
+
+    /// Whether the link is a byte stream, where a zero-length read means the peer
+    /// went away rather than an empty datagram.
+    pub fn is_stream(&self) -> bool {
+        matches!(self, Connection::Tcp(_) | Connection::Serial(_))
+    }
+
+    /// Writes one whole frame. Returns `Ok(false)` without sending when there is
+    /// nowhere to send yet: a `udpin` link only learns its peer once the peer has
+    /// spoken first.
+    pub fn send_frame(&mut self, bytes: &[u8]) -> io::Result<bool> {
+        match self.write_all(bytes) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Opens a second handle to the same link, e.g. to read on one thread and write
+    /// on another.
+    pub fn try_clone(&self) -> io::Result<Connection> {
+        Ok(match self {
+            Connection::Tcp(stream) => Connection::Tcp(stream.try_clone()?),
+            Connection::UdpIn { socket, peer } => Connection::UdpIn { socket: socket.try_clone()?, peer: peer.clone() },
+            Connection::UdpOut(socket) => Connection::UdpOut(socket.try_clone()?),
+            Connection::UdpBcast { socket, dest } => Connection::UdpBcast { socket: socket.try_clone()?, dest: *dest },
+            Connection::Serial(port) => Connection::Serial(port.try_clone().map_err(io::Error::from)?),
+        })
+    }
+
+    /// Limits how long a blocking read or write waits; `None` waits indefinitely. A
+    /// read that times out fails with `WouldBlock` or `TimedOut`, depending on the platform.
+    /// Sockets share the setting with their clones.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+            Connection::UdpIn { socket, .. }
+            | Connection::UdpOut(socket)
+            | Connection::UdpBcast { socket, .. } => {
+                socket.set_read_timeout(timeout)?;
+                socket.set_write_timeout(timeout)
+            }
+            Connection::Serial(port) => port.set_timeout(timeout.unwrap_or(SERIAL_BLOCKING_TIMEOUT)).map_err(io::Error::from),
+        }
+    }
+
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Connection::UdpIn { socket, .. }
+            | Connection::UdpOut(socket)
+            | Connection::UdpBcast { socket, .. } => socket.set_nonblocking(nonblocking),
+            // Serial ports have no non-blocking mode; a zero timeout makes reads return
+            // immediately, and `read` reports that as `WouldBlock` like the sockets do.
+            Connection::Serial(port) => {
+                let timeout = if nonblocking { Duration::ZERO } else { SERIAL_BLOCKING_TIMEOUT };
+                port.set_timeout(timeout).map_err(io::Error::from)
+            }
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            Connection::UdpIn { socket, peer } => {
+                let (amt, from) = socket.recv_from(buf)?;
+                *peer.lock().unwrap_or_else(PoisonError::into_inner) = Some(from);
+                Ok(amt)
+            }
+            Connection::UdpOut(socket) => socket.recv(buf),
+            Connection::UdpBcast { socket, .. } => socket.recv_from(buf).map(|(amt, _)| amt),
+            Connection::Serial(port) => match port.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => Err(io::ErrorKind::WouldBlock.into()),
+                other => other,
+            },
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            Connection::UdpIn { socket, peer } => match *peer.lock().unwrap_or_else(PoisonError::into_inner) {
+                Some(peer) => socket.send_to(buf, peer),
+                None => Err(io::Error::new(io::ErrorKind::NotConnected, "No UDP peer has sent a datagram yet")),
+            },
+            Connection::UdpOut(socket) => socket.send(buf),
+            Connection::UdpBcast { socket, dest } => socket.send_to(buf, *dest),
+            Connection::Serial(port) => port.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            Connection::Serial(port) => port.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Endpoint {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_each_scheme() {
+        assert_eq!(parse("tcpout:127.0.0.1:5760"), Endpoint::TcpOut("127.0.0.1:5760".into()));
+        assert_eq!(parse("tcpin:0.0.0.0:5760"), Endpoint::TcpIn("0.0.0.0:5760".into()));
+        assert_eq!(parse("udpin:0.0.0.0:14550"), Endpoint::UdpIn("0.0.0.0:14550".into()));
+        assert_eq!(parse("udpout:10.0.0.2:14550"), Endpoint::UdpOut("10.0.0.2:14550".into()));
+        assert_eq!(parse("udpbcast:10.0.0.255:14550"), Endpoint::UdpBcast("10.0.0.255:14550".into()));
+        assert_eq!(
+            parse("serial:/dev/ttyUSB0:57600"),
+            Endpoint::Serial { path: "/dev/ttyUSB0".into(), baud_rate: 57600 },
+        );
+    }
+
+    #[test]
+    fn serial_path_may_contain_colons() {
+        assert_eq!(parse("serial:COM3:115200"), Endpoint::Serial { path: "COM3".into(), baud_rate: 115200 });
+        assert_eq!(
+            parse("serial:/dev/serial/by-path/pci-0000:00:14.0-usb-0:1:1.0:921600"),
+            Endpoint::Serial { path: "/dev/serial/by-path/pci-0000:00:14.0-usb-0:1:1.0".into(), baud_rate: 921600 },
+        );
+    }
+
+    #[test]
+    fn bare_host_port_is_tcpout() {
+        assert_eq!(parse("127.0.0.1:5760"), Endpoint::TcpOut("127.0.0.1:5760".into()));
+        assert_eq!(parse("localhost:5760"), Endpoint::TcpOut("localhost:5760".into()));
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for s in ["tcpout:127.0.0.1:5760", "udpin:0.0.0.0:14550", "serial:/dev/ttyACM0:57600"] {
+            assert_eq!(parse(s).to_string(), s);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_strings() {
+        for s in ["", "localhost", "ftp:example.com", "serial:/dev/ttyUSB0", "serial:/dev/ttyUSB0:fast", "localhost:99999"] {
+            assert!(
+                matches!(s.parse::<Endpoint>(), Err(BlackboxError::InvalidConfig(_))),
+                "{:?} should be rejected",
+                s,
+            );
+        }
+    }
+}
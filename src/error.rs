@@ -0,0 +1,106 @@
+use crate::types::RecordKind;
+use mavlink::error::{MessageReadError, MessageWriteError};
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong in the library.
+#[derive(Debug)]
+pub enum BlackboxError {
+    /// Reading or writing a log file, or another local I/O operation, failed.
+    Io(io::Error),
+    /// A link could not be opened, read or written.
+    Link { endpoint: String, source: io::Error },
+    /// The file does not start with the `BBIN` magic.
+    InvalidMagic([u8; 4]),
+    /// The file was written in a BBIN version this build cannot read.
+    UnsupportedVersion(u16),
+    /// The log ends part-way through a record, e.g. because the capture was killed.
+    TruncatedRecord { offset: u64 },
+    /// A BBIN header, record header or index could not be encoded or decoded.
+    Bincode(bincode::Error),
+    /// A frame's checksum did not match its contents.
+    CrcMismatch { message_id: u32 },
+    /// A frame's message id is not part of the dialect.
+    UnknownMessage(u32),
+    /// A frame passed its checksum but its payload could not be parsed.
+    Decode(MessageReadError),
+    /// A message could not be encoded into a frame.
+    Encode(MessageWriteError),
+    /// A record holds a capture event or statistics, not a MAVLink frame.
+    NotAMessage(RecordKind),
+    /// A configuration value or connection string was rejected.
+    InvalidConfig(String),
+    /// A sink added with `SinkErrorPolicy::Abort` failed.
+    Sink(io::Error),
+    /// The capture thread panicked.
+    CapturePanicked,
+}
+
+impl fmt::Display for BlackboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlackboxError::Io(e) => write!(f, "I/O error: {}", e),
+            BlackboxError::Link { endpoint, source } => write!(f, "Link {} failed: {}", endpoint, source),
+            BlackboxError::InvalidMagic(magic) => write!(f, "Invalid BBIN file magic {:02X?}", magic),
+            BlackboxError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported BBIN version {} (expected {} or {} to {})",
+                version,
+                crate::types::BBIN_LEGACY_VERSION,
+                crate::types::BBIN_MIN_VERSION,
+                crate::types::BBIN_VERSION,
+            ),
+            BlackboxError::TruncatedRecord { offset } => write!(f, "Truncated record at offset {}", offset),
+            BlackboxError::Bincode(e) => write!(f, "Malformed BBIN data: {}", e),
+            BlackboxError::CrcMismatch { message_id } => write!(f, "CRC mismatch in message id {}", message_id),
+            BlackboxError::UnknownMessage(id) => write!(f, "Unknown message id {}", id),
+            BlackboxError::Decode(e) => write!(f, "Failed to parse MAVLink message: {}", e),
+            BlackboxError::Encode(e) => write!(f, "Failed to encode MAVLink message: {}", e),
+            BlackboxError::NotAMessage(kind) => write!(f, "Record holds {:?}, not a message", kind),
+            BlackboxError::InvalidConfig(detail) => write!(f, "{}", detail),
+            BlackboxError::Sink(e) => write!(f, "Sink failed: {}", e),
+            BlackboxError::CapturePanicked => write!(f, "Capture thread panicked"),
+        }
+    }
+}
+
+impl std::error::Error for BlackboxError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BlackboxError::Io(e) | BlackboxError::Sink(e) | BlackboxError::Link { source: e, .. } => Some(e),
+            BlackboxError::Bincode(e) => Some(e),
+            BlackboxError::Decode(e) => Some(e),
+            BlackboxError::Encode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BlackboxError {
+    fn from(e: io::Error) -> Self {
+        BlackboxError::Io(e)
+    }
+}
+
+impl From<bincode::Error> for BlackboxError {
+    fn from(e: bincode::Error) -> Self {
+        BlackboxError::Bincode(e)
+    }
+}
+
+impl From<MessageWriteError> for BlackboxError {
+    fn from(e: MessageWriteError) -> Self {
+        BlackboxError::Encode(e)
+    }
+}
+
+/// Lets library errors pass through `io::Result` code such as `MessageSink`s.
+impl From<BlackboxError> for io::Error {
+    fn from(e: BlackboxError) -> Self {
+        match e {
+            BlackboxError::Io(e) | BlackboxError::Sink(e) | BlackboxError::Link { source: e, .. } => e,
+            BlackboxError::InvalidConfig(_) => io::Error::new(io::ErrorKind::InvalidInput, e),
+            _ => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use crate::error::BlackboxError;
+use crate::framer::RawFrame;
+use crate::gcs::parse_message_id;
+use crate::types::UndecodedReason;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Which received frames are logged. Frames left out are still forwarded in proxy
+/// mode, counted in the traffic stats and used to track arming; they only skip the
+/// log and the sinks. Frames with a CRC mismatch are always logged, since their ids
+/// cannot be trusted.
+#[derive(Debug, Clone, Default)]
+pub struct CaptureFilter {
+    /// If not empty, only frames matching one of these rules are logged.
+    pub allow: Vec<FilterRule>,
+    /// Frames matching any of these rules are not logged, even if allowed.
+    pub deny: Vec<FilterRule>,
+    /// Upper bounds on how often a message type is logged per component. Frames
+    /// arriving faster are decimated.
+    pub max_rates: Vec<MaxRate>,
+}
+
+/// Matches frames by message id, system id and component id; `None` matches any.
+///
+/// Parses from `<message>[:<sysid>[:<compid>]]`, where the message is a name or
+/// id and any part can be `*`, e.g. `RAW_IMU`, `*:1` or `RAW_IMU:*:154`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FilterRule {
+    pub message_id: Option<u32>,
+    pub system_id: Option<u8>,
+    pub component_id: Option<u8>,
+}
+
+impl FilterRule {
+    pub fn matches(&self, message_id: u32, system_id: u8, component_id: u8) -> bool {
+        self.message_id.is_none_or(|id| id == message_id)
+            && self.system_id.is_none_or(|id| id == system_id)
+            && self.component_id.is_none_or(|id| id == component_id)
+    }
+}
+
+impl FromStr for FilterRule {
+    type Err = BlackboxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: &str| BlackboxError::InvalidConfig(format!("{}: {}", msg, s));
+
+        let mut parts = s.split(':');
+        let (message, system, component) = (parts.next(), parts.next(), parts.next());
+        if parts.next().is_some() {
+            return Err(invalid("Filter must be <message>[:<sysid>[:<compid>]]"));
+        }
+        let message_id = match message {
+            None | Some("*") => None,
+            Some(name) => Some(parse_message_id(name).ok_or_else(|| invalid("Unknown message name"))?),
+        };
+        let system_id = match system {
+            None | Some("*") => None,
+            Some(id) => Some(id.parse().map_err(|_| invalid("Invalid system id"))?),
+        };
+        let component_id = match component {
+            None | Some("*") => None,
+            Some(id) => Some(id.parse().map_err(|_| invalid("Invalid component id"))?),
+        };
+        Ok(FilterRule { message_id, system_id, component_id })
+    }
+}
+
+/// Logs a message at most `rate_hz` times a second from each component. A rate of 0
+/// logs none.
+///
+/// Parses from `<name or id>:<hz>`, e.g. `RAW_IMU:5`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaxRate {
+    pub message_id: u32,
+    pub rate_hz: f32,
+}
+
+impl FromStr for MaxRate {
+    type Err = BlackboxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: &str| BlackboxError::InvalidConfig(format!("{}: {}", msg, s));
+
+        let Some((name, rate)) = s.split_once(':') else {
+            return Err(invalid("Max rate must be <message>:<hz>"));
+        };
+        let message_id = parse_message_id(name).ok_or_else(|| invalid("Unknown message name"))?;
+        let rate_hz: f32 = rate.parse().map_err(|_| invalid("Invalid max rate"))?;
+        if rate_hz.is_nan() || rate_hz < 0.0 {
+            return Err(invalid("Max rate must not be negative"));
+        }
+        Ok(MaxRate { message_id, rate_hz })
+    }
+}
+
+/// Applies a `CaptureFilter` to the received frames and counts what it leaves out.
+pub(crate) struct FrameFilter {
+    config: CaptureFilter,
+    /// Timestamp in milliseconds from which each (system id, component id, message id)
+    /// under a `MaxRate` may be logged again.
+    next_due: HashMap<(u8, u8, u32), f64>,
+    pub(crate) filtered: u64,
+    pub(crate) decimated: u64,
+}
+
+impl FrameFilter {
+    pub(crate) fn new(config: CaptureFilter) -> Self {
+        FrameFilter { config, next_due: HashMap::new(), filtered: 0, decimated: 0 }
+    }
+
+    /// Whether a frame received at `timestamp` is to be logged.
+    pub(crate) fn keep(&mut self, frame: &RawFrame, timestamp: i64) -> bool {
+        if frame.defect == Some(UndecodedReason::CrcMismatch) {
+            return true;
+        }
+        let header = frame.header();
+        let message_id = frame.message_id();
+        let matches = |rule: &FilterRule| rule.matches(message_id, header.system_id, header.component_id);
+        if (!self.config.allow.is_empty() && !self.config.allow.iter().any(matches))
+            || self.config.deny.iter().any(matches)
+        {
+            self.filtered += 1;
+            return false;
+        }
+
+        let Some(max_rate) = self.config.max_rates.iter().find(|rate| rate.message_id == message_id) else {
+            return true;
+        };
+        let now = timestamp as f64;
+        let interval = 1000.0 / max_rate.rate_hz as f64;
+        let key = (header.system_id, header.component_id, message_id);
+        let keep = match self.next_due.get(&key) {
+            Some(&due) if now < due => false,
+            // Step from the previous due time so arrival jitter does not lower the
+            // rate, unless the message paused for longer than an interval.
+            Some(&due) if now - due < interval => {
+                self.next_due.insert(key, due + interval);
+                true
+            }
+            _ => {
+                self.next_due.insert(key, now + interval);
+                max_rate.rate_hz > 0.0
+            }
+        };
+        if !keep {
+            self.decimated += 1;
+        }
+        keep
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framer::MavFramer;
+    use mavlink::ardupilotmega::{MavMessage, ATTITUDE_DATA, HEARTBEAT_DATA};
+    use mavlink::{write_v2_msg, MavHeader};
+
+    fn frame(message: &MavMessage, system_id: u8, component_id: u8) -> RawFrame {
+        let mut bytes = Vec::new();
+        write_v2_msg(&mut bytes, MavHeader { sequence: 0, system_id, component_id }, message).unwrap();
+        let mut framer = MavFramer::new();
+        framer.push(&bytes);
+        framer.next_frame().unwrap()
+    }
+
+    fn attitude(system_id: u8, component_id: u8) -> RawFrame {
+        frame(&MavMessage::ATTITUDE(ATTITUDE_DATA::default()), system_id, component_id)
+    }
+
+    fn heartbeat(system_id: u8) -> RawFrame {
+        frame(&MavMessage::HEARTBEAT(HEARTBEAT_DATA::default()), system_id, 1)
+    }
+
+    fn rule(message_id: Option<u32>, system_id: Option<u8>, component_id: Option<u8>) -> FilterRule {
+        FilterRule { message_id, system_id, component_id }
+    }
+
+    #[test]
+    fn parses_filter_rules() {
+        assert_eq!("ATTITUDE".parse::<FilterRule>().unwrap(), rule(Some(30), None, None));
+        assert_eq!("30".parse::<FilterRule>().unwrap(), rule(Some(30), None, None));
+        assert_eq!("*:1".parse::<FilterRule>().unwrap(), rule(None, Some(1), None));
+        assert_eq!("RAW_IMU:*:154".parse::<FilterRule>().unwrap(), rule(Some(27), None, Some(154)));
+        for s in ["NOT_A_MESSAGE", "ATTITUDE:x", "ATTITUDE:1:256", "ATTITUDE:1:1:1"] {
+            assert!(matches!(s.parse::<FilterRule>(), Err(BlackboxError::InvalidConfig(_))), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn parses_max_rates() {
+        assert_eq!("RAW_IMU:5".parse::<MaxRate>().unwrap(), MaxRate { message_id: 27, rate_hz: 5.0 });
+        assert_eq!("30:0.5".parse::<MaxRate>().unwrap(), MaxRate { message_id: 30, rate_hz: 0.5 });
+        for s in ["RAW_IMU", "RAW_IMU:fast", "RAW_IMU:-1", "RAW_IMU:NaN", "NOT_A_MESSAGE:5"] {
+            assert!(matches!(s.parse::<MaxRate>(), Err(BlackboxError::InvalidConfig(_))), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn deny_overrides_allow() {
+        let mut filter = FrameFilter::new(CaptureFilter {
+            allow: vec!["*:1".parse().unwrap()],
+            deny: vec!["ATTITUDE:*:2".parse().unwrap()],
+            max_rates: Vec::new(),
+        });
+        assert!(filter.keep(&heartbeat(1), 0));
+        assert!(filter.keep(&attitude(1, 1), 0));
+        assert!(!filter.keep(&attitude(1, 2), 0));
+        assert!(!filter.keep(&heartbeat(2), 0));
+        assert_eq!(filter.filtered, 2);
+    }
+
+    #[test]
+    fn crc_mismatch_is_always_kept() {
+        let mut filter = FrameFilter::new(CaptureFilter {
+            deny: vec!["*".parse().unwrap()],
+            ..Default::default()
+        });
+        let mut corrupt = heartbeat(1);
+        corrupt.defect = Some(UndecodedReason::CrcMismatch);
+        assert!(filter.keep(&corrupt, 0));
+        assert!(!filter.keep(&heartbeat(1), 0));
+    }
+
+    #[test]
+    fn decimates_to_max_rate_per_component() {
+        let mut filter = FrameFilter::new(CaptureFilter {
+            max_rates: vec!["ATTITUDE:10".parse().unwrap()],
+            ..Default::default()
+        });
+        // One second of 50 Hz ATTITUDE from two components, with some jitter.
+        let mut kept = [0, 0];
+        for i in 0..50 {
+            let timestamp = i * 20 + (i % 3);
+            for (component, kept) in (1..).zip(kept.iter_mut()) {
+                if filter.keep(&attitude(1, component), timestamp) {
+                    *kept += 1;
+                }
+            }
+            assert!(filter.keep(&heartbeat(1), timestamp));
+        }
+        assert_eq!(kept, [10, 10]);
+        assert_eq!(filter.decimated, 80);
+    }
+
+    #[test]
+    fn resumes_after_a_pause() {
+        let mut filter = FrameFilter::new(CaptureFilter {
+            max_rates: vec!["ATTITUDE:1".parse().unwrap()],
+            ..Default::default()
+        });
+        assert!(filter.keep(&attitude(1, 1), 0));
+        assert!(!filter.keep(&attitude(1, 1), 500));
+        assert!(filter.keep(&attitude(1, 1), 10_000));
+        assert!(!filter.keep(&attitude(1, 1), 10_999));
+        assert!(filter.keep(&attitude(1, 1), 11_000));
+    }
+
+    #[test]
+    fn zero_rate_logs_none() {
+        let mut filter = FrameFilter::new(CaptureFilter {
+            max_rates: vec!["ATTITUDE:0".parse().unwrap()],
+            ..Default::default()
+        });
+        assert!((0..10).all(|i| !filter.keep(&attitude(1, 1), i * 1000)));
+        assert_eq!(filter.decimated, 10);
+    }
+}
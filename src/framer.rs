@@ -0,0 +1,328 @@
+use crate::types::UndecodedReason;
+use mavlink::ardupilotmega::MavMessage;
+use mavlink::{read_v1_raw_message, read_v2_raw_message, MavHeader, MavlinkVersion, Message, MAV_STX, MAV_STX_V2};
+
+/// Lengths of the MAVLink v1 and v2 headers including the STX marker.
+const V1_HEADER_LEN: usize = 6;
+const V2_HEADER_LEN: usize = 10;
+const CHECKSUM_LEN: usize = 2;
+const SIGNATURE_LEN: usize = 13;
+const IFLAG_SIGNED: u8 = 0x01;
+
+/// A complete MAVLink frame exactly as it was received.
+#[derive(Debug, Clone)]
+pub struct RawFrame {
+    pub version: MavlinkVersion,
+    pub bytes: Vec<u8>,
+    /// Set when the frame cannot be trusted to decode: its message id is not in
+    /// the dialect, or its CRC did not match.
+    pub defect: Option<UndecodedReason>,
+}
+
+impl RawFrame {
+    pub fn header(&self) -> MavHeader {
+        // v2 carries two flag bytes between the length and the sequence number.
+        let offset = match self.version {
+            MavlinkVersion::V1 => 2,
+            MavlinkVersion::V2 => 4,
+        };
+        MavHeader {
+            sequence: self.bytes[offset],
+            system_id: self.bytes[offset + 1],
+            component_id: self.bytes[offset + 2],
+        }
+    }
+
+    pub fn message_id(&self) -> u32 {
+        match self.version {
+            MavlinkVersion::V1 => self.bytes[5] as u32,
+            MavlinkVersion::V2 => u32::from_le_bytes([self.bytes[7], self.bytes[8], self.bytes[9], 0]),
+        }
+    }
+}
+
+/// Counters kept by [`MavFramer`] while it scans the incoming byte stream.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FramerStats {
+    /// Complete, CRC-valid frames handed out.
+    pub frames: u64,
+    /// Frames handed out unchecked because their message id is not in the dialect.
+    pub unknown_messages: u64,
+    /// Bytes skipped while looking for the next STX marker, including stray STX
+    /// bytes and the markers of frames rejected for a bad CRC.
+    pub bytes_discarded: u64,
+    /// Frames rejected because their CRC did not match. Stray STX bytes that only
+    /// look like the start of a frame are not counted.
+    pub crc_errors: u64,
+}
+
+impl std::ops::AddAssign for FramerStats {
+    fn add_assign(&mut self, other: FramerStats) {
+        self.frames += other.frames;
+        self.unknown_messages += other.unknown_messages;
+        self.bytes_discarded += other.bytes_discarded;
+        self.crc_errors += other.crc_errors;
+    }
+}
+
+/// Accumulates bytes from a stream or datagram link and splits them into
+/// MAVLink v1 and v2 frames, no matter how the transport chunked them.
+///
+/// Bytes before an STX marker (0xFE or 0xFD) are discarded. Frames with a message id
+/// the dialect does not know are handed out whole, marked `UnknownMessage`, since
+/// their CRC cannot be checked. A candidate whose CRC does not match is handed out as
+/// a `CrcMismatch` copy but only costs its STX byte, so a real frame starting inside
+/// it is still found. Either kind is only handed out when another STX or the end of
+/// the data follows it; otherwise its STX is taken to be a stray byte and skipped.
+#[derive(Debug, Default)]
+pub struct MavFramer {
+    buf: Vec<u8>,
+    stats: FramerStats,
+}
+
+impl MavFramer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends freshly received bytes to the framing buffer.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Returns the next complete frame, or `None` until more bytes are pushed.
+    pub fn next_frame(&mut self) -> Option<RawFrame> {
+        loop {
+            match self.buf.iter().position(|&b| b == MAV_STX || b == MAV_STX_V2) {
+                Some(start) => self.discard(start),
+                None => {
+                    let len = self.buf.len();
+                    self.discard(len);
+                    return None;
+                }
+            }
+
+            let version = if self.buf[0] == MAV_STX { MavlinkVersion::V1 } else { MavlinkVersion::V2 };
+            let header_len = match version {
+                MavlinkVersion::V1 => V1_HEADER_LEN,
+                MavlinkVersion::V2 => V2_HEADER_LEN,
+            };
+            if self.buf.len() < header_len {
+                return None;
+            }
+            let payload_len = self.buf[1] as usize;
+            let signature_len = match version {
+                MavlinkVersion::V2 if self.buf[2] & IFLAG_SIGNED != 0 => SIGNATURE_LEN,
+                _ => 0,
+            };
+            let frame_len = header_len + payload_len + CHECKSUM_LEN + signature_len;
+            if self.buf.len() < frame_len {
+                return None;
+            }
+
+            // v2 defines no incompatibility flag besides signing, so anything else is
+            // a stray STX in the middle of other data.
+            if version == MavlinkVersion::V2 && self.buf[2] & !IFLAG_SIGNED != 0 {
+                self.discard(1);
+                continue;
+            }
+
+            let msg_id = match version {
+                MavlinkVersion::V1 => self.buf[5] as u32,
+                MavlinkVersion::V2 => u32::from_le_bytes([self.buf[7], self.buf[8], self.buf[9], 0]),
+            };
+            // Neither an unknown message id nor a bad CRC shows that a frame really
+            // starts here, rather than a stray STX in other data. Such a candidate only
+            // counts as a frame if the next one starts right after it or the data ends
+            // there; otherwise it is skipped like any other garbage.
+            let followed = self.buf.get(frame_len).is_none_or(|&b| b == MAV_STX || b == MAV_STX_V2);
+            if MavMessage::default_message_from_id(msg_id).is_err() {
+                if !followed {
+                    self.discard(1);
+                    continue;
+                }
+                self.stats.unknown_messages += 1;
+                let bytes = self.buf.drain(..frame_len).collect();
+                return Some(RawFrame { version, bytes, defect: Some(UndecodedReason::UnknownMessage) });
+            }
+
+            let mut candidate = &self.buf[..frame_len];
+            let valid = match version {
+                MavlinkVersion::V1 => read_v1_raw_message(&mut candidate).map(|raw| raw.has_valid_crc::<MavMessage>()),
+                MavlinkVersion::V2 => read_v2_raw_message(&mut candidate).map(|raw| raw.has_valid_crc::<MavMessage>()),
+            }
+            .unwrap_or(false);
+            if !valid {
+                if !followed {
+                    self.discard(1);
+                    continue;
+                }
+                self.stats.crc_errors += 1;
+                let bytes = self.buf[..frame_len].to_vec();
+                self.discard(1);
+                return Some(RawFrame { version, bytes, defect: Some(UndecodedReason::CrcMismatch) });
+            }
+
+            self.stats.frames += 1;
+            let bytes = self.buf.drain(..frame_len).collect();
+            return Some(RawFrame { version, bytes, defect: None });
+        }
+    }
+
+    /// Drops any partial frame, e.g. after the link was re-established.
+    pub fn reset(&mut self) {
+        let len = self.buf.len();
+        self.discard(len);
+    }
+
+    pub fn stats(&self) -> FramerStats {
+        self.stats
+    }
+
+    fn discard(&mut self, count: usize) {
+        self.buf.drain(..count);
+        self.stats.bytes_discarded += count as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mavlink::ardupilotmega::{ATTITUDE_DATA, HEARTBEAT_DATA};
+    use mavlink::{read_v2_raw_message, write_v1_msg, write_v2_msg};
+
+    fn header(sequence: u8) -> MavHeader {
+        MavHeader { sequence, system_id: 1, component_id: 1 }
+    }
+
+    fn heartbeat_v2(sequence: u8) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_v2_msg(&mut bytes, header(sequence), &MavMessage::HEARTBEAT(HEARTBEAT_DATA::default())).unwrap();
+        bytes
+    }
+
+    fn frames(framer: &mut MavFramer) -> Vec<RawFrame> {
+        std::iter::from_fn(|| framer.next_frame()).collect()
+    }
+
+    #[test]
+    fn reassembles_frame_split_across_pushes() {
+        let frame = heartbeat_v2(7);
+        let mut framer = MavFramer::new();
+        for (i, byte) in frame.iter().enumerate() {
+            framer.push(&[*byte]);
+            let next = framer.next_frame();
+            assert_eq!(next.is_some(), i == frame.len() - 1);
+            if let Some(next) = next {
+                assert_eq!(next.bytes, frame);
+                assert_eq!(next.defect, None);
+                assert_eq!(next.header().sequence, 7);
+            }
+        }
+        assert_eq!(framer.stats().frames, 1);
+        assert_eq!(framer.stats().bytes_discarded, 0);
+    }
+
+    #[test]
+    fn discards_garbage_before_stx() {
+        let mut framer = MavFramer::new();
+        framer.push(&[0x00, 0x11, 0x22]);
+        framer.push(&heartbeat_v2(0));
+        let frames = frames(&mut framer);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].defect, None);
+        assert_eq!(framer.stats().bytes_discarded, 3);
+    }
+
+    #[test]
+    fn stray_stx_does_not_swallow_the_next_frame() {
+        let mut framer = MavFramer::new();
+        framer.push(&[0xFD, 0x05, 0x00, 0x00, 0x00, 0x00, 0x00, 0xAA, 0xBB, 0xCC]);
+        framer.push(&heartbeat_v2(0));
+        let frames = frames(&mut framer);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].bytes, heartbeat_v2(0));
+        assert_eq!(frames[0].defect, None);
+        assert_eq!(framer.stats().unknown_messages, 0);
+        assert_eq!(framer.stats().crc_errors, 0);
+    }
+
+    #[test]
+    fn false_stx_inside_payload_is_skipped() {
+        // Joining the stream mid-frame, with 0xFD 0x02 in the payload left over.
+        let attitude = MavMessage::ATTITUDE(ATTITUDE_DATA { time_boot_ms: 0x02FD, ..Default::default() });
+        let mut partial = Vec::new();
+        write_v2_msg(&mut partial, header(0), &attitude).unwrap();
+        let mut framer = MavFramer::new();
+        framer.push(&partial[3..]);
+        framer.push(&heartbeat_v2(1));
+        framer.push(&heartbeat_v2(2));
+        let frames = frames(&mut framer);
+        assert_eq!(frames.len(), 2);
+        assert!(frames.iter().all(|frame| frame.defect.is_none()));
+        assert_eq!(framer.stats().crc_errors, 0);
+    }
+
+    #[test]
+    fn crc_mismatch_is_reported_and_the_next_frame_kept() {
+        let mut corrupt = heartbeat_v2(0);
+        corrupt[10] ^= 0xFF;
+        let mut framer = MavFramer::new();
+        framer.push(&corrupt);
+        framer.push(&heartbeat_v2(1));
+        let frames = frames(&mut framer);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].bytes, corrupt);
+        assert_eq!(frames[0].defect, Some(UndecodedReason::CrcMismatch));
+        assert_eq!(frames[1].defect, None);
+        assert_eq!(framer.stats().crc_errors, 1);
+    }
+
+    #[test]
+    fn unknown_message_id_is_handed_out_whole() {
+        let mut unknown = heartbeat_v2(0);
+        unknown[7..10].copy_from_slice(&[0xEF, 0xCD, 0xAB]);
+        let mut framer = MavFramer::new();
+        framer.push(&unknown);
+        framer.push(&heartbeat_v2(1));
+        let frames = frames(&mut framer);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].defect, Some(UndecodedReason::UnknownMessage));
+        assert_eq!(frames[0].message_id(), 0xABCDEF);
+        assert_eq!(frames[1].defect, None);
+    }
+
+    #[test]
+    fn signed_v2_frame_includes_its_signature() {
+        let mut signed = heartbeat_v2(3);
+        signed[2] |= IFLAG_SIGNED;
+        signed.extend_from_slice(&[0x5A; SIGNATURE_LEN]);
+        let crc = read_v2_raw_message(&mut signed.as_slice()).unwrap().calculate_crc::<MavMessage>();
+        let crc_pos = V2_HEADER_LEN + signed[1] as usize;
+        signed[crc_pos..crc_pos + CHECKSUM_LEN].copy_from_slice(&crc.to_le_bytes());
+
+        let mut framer = MavFramer::new();
+        framer.push(&signed);
+        framer.push(&heartbeat_v2(4));
+        let frames = frames(&mut framer);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].bytes, signed);
+        assert_eq!(frames[0].defect, None);
+        assert_eq!(frames[1].header().sequence, 4);
+    }
+
+    #[test]
+    fn v1_frame() {
+        let mut bytes = Vec::new();
+        write_v1_msg(&mut bytes, header(9), &MavMessage::HEARTBEAT(HEARTBEAT_DATA::default())).unwrap();
+        let mut framer = MavFramer::new();
+        framer.push(&bytes);
+        let frame = framer.next_frame().unwrap();
+        assert_eq!(frame.version, MavlinkVersion::V1);
+        assert_eq!(frame.bytes, bytes);
+        assert_eq!(frame.defect, None);
+        assert_eq!(frame.message_id(), 0);
+        assert_eq!(frame.header().sequence, 9);
+        assert!(framer.next_frame().is_none());
+    }
+}
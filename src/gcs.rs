@@ -0,0 +1,198 @@
+use crate::error::BlackboxError;
+use mavlink::ardupilotmega::{
+    MavAutopilot, MavCmd, MavDataStream, MavMessage, MavModeFlag, MavState, MavType, COMMAND_LONG_DATA,
+    HEARTBEAT_DATA, REQUEST_DATA_STREAM_DATA,
+};
+use mavlink::{MavHeader, Message};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Makes `BlackBoxer` announce itself as a ground station and ask each autopilot it
+/// sees for telemetry, since ArduPilot streams little to a client that stays silent.
+#[derive(Debug, Clone)]
+pub struct GcsEmulation {
+    pub system_id: u8,
+    pub component_id: u8,
+    pub heartbeat_interval: Duration,
+    /// How often the rate table is sent again, so an autopilot that rebooted
+    /// resumes streaming.
+    pub request_interval: Duration,
+    pub rates: Vec<RateRequest>,
+}
+
+impl Default for GcsEmulation {
+    fn default() -> Self {
+        GcsEmulation {
+            system_id: 255,
+            component_id: 190, // MAV_COMP_ID_MISSIONPLANNER
+            heartbeat_interval: Duration::from_secs(1),
+            request_interval: Duration::from_secs(10),
+            rates: vec![RateRequest::DataStream { stream: MavDataStream::MAV_DATA_STREAM_ALL, rate_hz: 4 }],
+        }
+    }
+}
+
+/// One entry of the rate table.
+///
+/// Parses from `stream:<name>:<hz>`, e.g. `stream:EXTENDED_STATUS:2`, or
+/// `message:<name or id>:<hz>`, e.g. `message:ATTITUDE:10`. A rate of 0 stops the
+/// stream or message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RateRequest {
+    /// `REQUEST_DATA_STREAM` for one of ArduPilot's stream groups.
+    DataStream { stream: MavDataStream, rate_hz: u16 },
+    /// `MAV_CMD_SET_MESSAGE_INTERVAL` for a single message id.
+    MessageInterval { message_id: u32, rate_hz: f32 },
+}
+
+impl RateRequest {
+    fn to_message(&self, target_system: u8, target_component: u8) -> MavMessage {
+        match *self {
+            RateRequest::DataStream { stream, rate_hz } => MavMessage::REQUEST_DATA_STREAM(REQUEST_DATA_STREAM_DATA {
+                req_message_rate: rate_hz,
+                target_system,
+                target_component,
+                req_stream_id: stream as u8,
+                start_stop: u8::from(rate_hz > 0),
+            }),
+            RateRequest::MessageInterval { message_id, rate_hz } => {
+                // Interval in microseconds; -1 disables the message.
+                let interval = if rate_hz > 0.0 { 1_000_000.0 / rate_hz } else { -1.0 };
+                MavMessage::COMMAND_LONG(COMMAND_LONG_DATA {
+                    command: MavCmd::MAV_CMD_SET_MESSAGE_INTERVAL,
+                    param1: message_id as f32,
+                    param2: interval,
+                    target_system,
+                    target_component,
+                    ..Default::default()
+                })
+            }
+        }
+    }
+}
+
+impl FromStr for RateRequest {
+    type Err = BlackboxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: &str| BlackboxError::InvalidConfig(format!("{}: {}", msg, s));
+
+        let mut parts = s.split(':');
+        let (Some(kind), Some(name), Some(rate), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid("Rate must be stream:<name>:<hz> or message:<name>:<hz>"));
+        };
+        match kind {
+            "stream" => {
+                let stream = parse_data_stream(name).ok_or_else(|| invalid("Unknown data stream"))?;
+                let rate_hz = rate.parse().map_err(|_| invalid("Invalid stream rate"))?;
+                Ok(RateRequest::DataStream { stream, rate_hz })
+            }
+            "message" => {
+                let message_id = parse_message_id(name).ok_or_else(|| invalid("Unknown message name"))?;
+                let rate_hz: f32 = rate.parse().map_err(|_| invalid("Invalid message rate"))?;
+                if rate_hz.is_nan() || rate_hz < 0.0 {
+                    return Err(invalid("Message rate must not be negative"));
+                }
+                Ok(RateRequest::MessageInterval { message_id, rate_hz })
+            }
+            _ => Err(invalid("Rate must be stream:<name>:<hz> or message:<name>:<hz>")),
+        }
+    }
+}
+
+/// Accepts a message id or a message name in any case, e.g. `attitude`.
+pub(crate) fn parse_message_id(name: &str) -> Option<u32> {
+    name.parse().ok().or_else(|| MavMessage::message_id_from_name(&name.to_uppercase()).ok())
+}
+
+/// Accepts a stream group with or without the `MAV_DATA_STREAM_` prefix.
+fn parse_data_stream(name: &str) -> Option<MavDataStream> {
+    let name = name.to_uppercase();
+    let stream = match name.strip_prefix("MAV_DATA_STREAM_").unwrap_or(&name) {
+        "ALL" => MavDataStream::MAV_DATA_STREAM_ALL,
+        "RAW_SENSORS" => MavDataStream::MAV_DATA_STREAM_RAW_SENSORS,
+        "EXTENDED_STATUS" => MavDataStream::MAV_DATA_STREAM_EXTENDED_STATUS,
+        "RC_CHANNELS" => MavDataStream::MAV_DATA_STREAM_RC_CHANNELS,
+        "RAW_CONTROLLER" => MavDataStream::MAV_DATA_STREAM_RAW_CONTROLLER,
+        "POSITION" => MavDataStream::MAV_DATA_STREAM_POSITION,
+        "EXTRA1" => MavDataStream::MAV_DATA_STREAM_EXTRA1,
+        "EXTRA2" => MavDataStream::MAV_DATA_STREAM_EXTRA2,
+        "EXTRA3" => MavDataStream::MAV_DATA_STREAM_EXTRA3,
+        _ => return None,
+    };
+    Some(stream)
+}
+
+/// The heartbeat a ground station sends to announce itself.
+pub(crate) fn gcs_heartbeat() -> MavMessage {
+    MavMessage::HEARTBEAT(HEARTBEAT_DATA {
+        custom_mode: 0,
+        mavtype: MavType::MAV_TYPE_GCS,
+        autopilot: MavAutopilot::MAV_AUTOPILOT_INVALID,
+        base_mode: MavModeFlag::empty(),
+        system_status: MavState::MAV_STATE_ACTIVE,
+        mavlink_version: 3,
+    })
+}
+
+/// Keeps the heartbeat and rate request schedule for a `GcsEmulation`.
+pub(crate) struct GcsEmulator {
+    config: GcsEmulation,
+    sequence: u8,
+    next_heartbeat: Instant,
+    next_request: Instant,
+    /// Component id of the autopilot of each system seen so far.
+    autopilots: HashMap<u8, u8>,
+}
+
+impl GcsEmulator {
+    pub(crate) fn new(config: GcsEmulation) -> Self {
+        let now = Instant::now();
+        GcsEmulator { config, sequence: 0, next_heartbeat: now, next_request: now, autopilots: HashMap::new() }
+    }
+
+    /// Notes an autopilot heartbeat; a newly seen autopilot gets the rate table right away.
+    pub(crate) fn autopilot_seen(&mut self, system_id: u8, component_id: u8) {
+        if self.autopilots.insert(system_id, component_id) != Some(component_id) {
+            self.next_request = Instant::now();
+        }
+    }
+
+    /// When `due_messages` will next have something to send.
+    pub(crate) fn next_due(&self) -> Instant {
+        if self.autopilots.is_empty() {
+            self.next_heartbeat
+        } else {
+            self.next_heartbeat.min(self.next_request)
+        }
+    }
+
+    /// Returns the messages due to be sent at `now`, each with its own header.
+    pub(crate) fn due_messages(&mut self, now: Instant) -> Vec<(MavHeader, MavMessage)> {
+        let mut messages = Vec::new();
+        if now >= self.next_heartbeat {
+            self.next_heartbeat = now + self.config.heartbeat_interval;
+            messages.push(gcs_heartbeat());
+        }
+        if now >= self.next_request && !self.autopilots.is_empty() {
+            self.next_request = now + self.config.request_interval;
+            for (&system_id, &component_id) in &self.autopilots {
+                messages.extend(self.config.rates.iter().map(|rate| rate.to_message(system_id, component_id)));
+            }
+        }
+
+        messages
+            .into_iter()
+            .map(|message| {
+                let header = MavHeader {
+                    system_id: self.config.system_id,
+                    component_id: self.config.component_id,
+                    sequence: self.sequence,
+                };
+                self.sequence = self.sequence.wrapping_add(1);
+                (header, message)
+            })
+            .collect()
+    }
+}
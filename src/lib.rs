@@ -1,14 +1,36 @@
+#[cfg(feature = "async")]
+pub mod async_io;
 pub mod blackboxer;
+pub mod connection;
+pub mod error;
+pub mod filter;
+pub mod framer;
+pub mod gcs;
 pub mod bbin_reader;
 pub mod bbin_replayer;
 pub mod bbin_writer;
+pub mod session;
+pub mod sink;
+pub mod stats;
 pub mod types;
+pub mod ui_queue;
 
 
-pub use blackboxer::{BlackBoxer, BlackBoxerConfig};
+#[cfg(feature = "async")]
+pub use async_io::{AsyncBbinReader, AsyncBbinReplayer, CaptureStream};
+pub use blackboxer::{BlackBoxer, BlackBoxerConfig, ReconnectPolicy, RotationPolicy};
 pub use bbin_reader::{BbinReader};
 pub use bbin_replayer::{BbinReplayer};
 pub use bbin_writer::{BbinWriter};
-pub use types::{LoggedMessage, LoggedMessageHeader};
+pub use connection::{Connection, Endpoint};
+pub use error::BlackboxError;
+pub use filter::{CaptureFilter, FilterRule, MaxRate};
+pub use session::{CaptureHandle, CaptureStats, CaptureSummary};
+pub use sink::{CallbackSink, ChannelSink, MessageSink, SinkErrorPolicy};
+pub use stats::{ComponentStats, MessageTypeStats, TrafficStats};
+pub use framer::{FramerStats, MavFramer, RawFrame};
+pub use gcs::{GcsEmulation, RateRequest};
+pub use ui_queue::{UiOverflow, UiQueueStats, UiReceiver};
+pub use types::{BbinHeader, BbinRecord, CaptureEvent, Direction, LoggedMessage, LoggedMessageHeader, RecordKind, UndecodedReason};
 
 
@@ -0,0 +1,116 @@
+use crate::blackboxer::LinkInput;
+use crate::error::BlackboxError;
+use crate::framer::FramerStats;
+use crate::stats::TrafficStats;
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Counters of a running capture, refreshed on every pass of the capture loop.
+#[derive(Debug, Clone, Default)]
+pub struct CaptureStats {
+    /// Framer counters summed over all links, including ones that have closed.
+    pub framer: FramerStats,
+    /// Message and undecoded records written to the built-in log, over all segments.
+    pub frames_written: u64,
+    /// Bytes written to the built-in log, over all segments.
+    pub bytes_written: u64,
+    /// Frames dropped because another link delivered them first.
+    pub duplicates_dropped: u64,
+    /// Frames left out of the log by the allow and deny rules of the `CaptureFilter`.
+    pub frames_filtered: u64,
+    /// Frames left out of the log by its `MaxRate`s.
+    pub frames_decimated: u64,
+    /// Links currently connected.
+    pub links_up: usize,
+    /// Log file being written.
+    pub current_file: Option<PathBuf>,
+    pub paused: bool,
+    /// Sinks still receiving records, besides the built-in log.
+    pub sinks: usize,
+    /// Errors returned by sinks, including ones that were removed for it.
+    pub sink_errors: u64,
+    /// Received traffic per component, refreshed once a second with its rates.
+    pub traffic: TrafficStats,
+}
+
+/// What a finished capture produced.
+#[derive(Debug, Clone)]
+pub struct CaptureSummary {
+    /// Every log file written, in order.
+    pub files: Vec<PathBuf>,
+    /// Counters as of the end of the capture.
+    pub stats: CaptureStats,
+    pub duration: Duration,
+}
+
+/// A capture running on its own thread, returned by `BlackBoxer::start`.
+///
+/// Dropping the handle without `stop` or `join` tells the capture to stop as well,
+/// but does not wait for it: the log is finalized on the capture thread shortly after,
+/// and lost if the process exits first. Call `stop` to be sure it was.
+pub struct CaptureHandle {
+    /// Taken by `join`.
+    pub(crate) thread: Option<JoinHandle<Result<Vec<PathBuf>, BlackboxError>>>,
+    pub(crate) stop_flag: Arc<AtomicBool>,
+    pub(crate) paused: Arc<AtomicBool>,
+    pub(crate) stats: Arc<Mutex<CaptureStats>>,
+    /// Wakes the capture loop so it sees a stop or pause without waiting for traffic.
+    pub(crate) wake: Sender<LinkInput>,
+    pub(crate) started: Instant,
+}
+
+impl CaptureHandle {
+    /// Stops the capture and waits until the current log file is finalized.
+    pub fn stop(self) -> Result<CaptureSummary, BlackboxError> {
+        self.request_stop();
+        self.join()
+    }
+
+    fn request_stop(&self) {
+        self.stop_flag.store(true, Ordering::Relaxed);
+        let _ = self.wake.send(LinkInput::Wake);
+    }
+
+    /// Stops writing to the log until `resume`. Links stay open and are still read,
+    /// forwarded and answered; the log records when recording was paused.
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+        let _ = self.wake.send(LinkInput::Wake);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+        let _ = self.wake.send(LinkInput::Wake);
+    }
+
+    pub fn stats(&self) -> CaptureStats {
+        self.stats.lock().map(|stats| stats.clone()).unwrap_or_default()
+    }
+
+    /// Whether the capture has ended on its own, e.g. after every link was lost.
+    pub fn is_finished(&self) -> bool {
+        self.thread.as_ref().is_none_or(JoinHandle::is_finished)
+    }
+
+    /// Waits for the capture to end on its own and returns what it produced.
+    pub fn join(mut self) -> Result<CaptureSummary, BlackboxError> {
+        let files = self.thread
+            .take()
+            .ok_or(BlackboxError::CapturePanicked)?
+            .join()
+            .map_err(|_| BlackboxError::CapturePanicked)??;
+        let stats = self.stats.lock().map(|stats| stats.clone()).unwrap_or_default();
+        Ok(CaptureSummary { files, stats, duration: self.started.elapsed() })
+    }
+}
+
+impl Drop for CaptureHandle {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            self.request_stop();
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use crate::connection::Connection;
+use crate::types::{BbinRecord, LoggedMessage, UndecodedReason};
+use crate::ui_queue::{UiOverflow, UiQueue, UiReceiver};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::sync::mpsc::{self, Sender, SyncSender};
+use std::thread;
+#[cfg(feature = "async")]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Somewhere captured records go. `BlackBoxer` writes its own rotating BBIN log and
+/// hands every record to each sink registered with `BlackBoxer::add_sink` as well.
+pub trait MessageSink: Send {
+    /// Receives every record in the order it is logged: messages, undecoded frames
+    /// and capture events.
+    fn write_record(&mut self, record: &BbinRecord) -> io::Result<()>;
+
+    /// Called when a vehicle arms or disarms, with the heartbeat that reported it.
+    /// The change is seen even while recording is paused or waiting for arming.
+    fn arm_state_changed(&mut self, _message: &LoggedMessage) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called once when the capture ends, or when the sink is removed after an error.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What `BlackBoxer` does when a sink returns an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkErrorPolicy {
+    /// End the capture with the error, as an error writing the built-in log does.
+    Abort,
+    /// Report the error, finish the sink and stop using it.
+    Remove,
+    /// Report the error and keep passing records to the sink.
+    Ignore,
+}
+
+/// Pushes decoded messages to a UI as `LoggedMessage`s, plus an `ARM_STATE` message
+/// whenever a vehicle arms or disarms. Fails once the receiver has been dropped.
+pub struct ChannelSink {
+    target: ChannelTarget,
+    /// Arm state per system id, from the arm changes seen so far.
+    armed: HashMap<u8, bool>,
+}
+
+enum ChannelTarget {
+    Unbounded(Sender<LoggedMessage>),
+    Bounded(Arc<UiQueue>),
+    /// `UiOverflow::Block`: a delivery thread waits for room in the queue, so the
+    /// capture never does. The hand-off to it holds at most the queue's capacity.
+    /// Dropping the sender lets it drain and close the queue.
+    Blocking {
+        tx: Option<SyncSender<LoggedMessage>>,
+        queue: Arc<UiQueue>,
+    },
+    /// Feeds a `CaptureStream`, counting messages that find the channel full.
+    #[cfg(feature = "async")]
+    Tokio {
+        tx: tokio::sync::mpsc::Sender<LoggedMessage>,
+        dropped: Arc<AtomicU64>,
+    },
+}
+
+impl ChannelSink {
+    /// Sends to an unbounded channel, which grows without limit if the UI stalls.
+    pub fn new(tx: Sender<LoggedMessage>) -> Self {
+        ChannelSink { target: ChannelTarget::Unbounded(tx), armed: HashMap::new() }
+    }
+
+    /// Queues at most `capacity` messages for the UI and applies `overflow` once the
+    /// queue is full. The log is written before the UI is served either way.
+    pub fn bounded(capacity: usize, overflow: UiOverflow) -> (Self, UiReceiver) {
+        let queue = UiQueue::new(capacity, overflow);
+        let target = match overflow {
+            UiOverflow::Block => {
+                let (tx, rx) = mpsc::sync_channel::<LoggedMessage>(capacity.max(1));
+                let delivery = queue.clone();
+                thread::spawn(move || {
+                    for message in rx {
+                        if delivery.deliver(message).is_err() {
+                            break;
+                        }
+                    }
+                    delivery.close();
+                });
+                ChannelTarget::Blocking { tx: Some(tx), queue: queue.clone() }
+            }
+            _ => ChannelTarget::Bounded(queue.clone()),
+        };
+        (ChannelSink { target, armed: HashMap::new() }, UiReceiver { queue })
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn tokio(tx: tokio::sync::mpsc::Sender<LoggedMessage>, dropped: Arc<AtomicU64>) -> Self {
+        ChannelSink { target: ChannelTarget::Tokio { tx, dropped }, armed: HashMap::new() }
+    }
+
+    fn send(&self, message: LoggedMessage, decimate: bool) -> io::Result<()> {
+        match &self.target {
+            ChannelTarget::Unbounded(tx) => tx
+                .send(message)
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "UI receiver dropped")),
+            ChannelTarget::Bounded(queue) => queue.push(message, decimate),
+            ChannelTarget::Blocking { tx: Some(tx), queue } => queue.hand_off(tx, message),
+            ChannelTarget::Blocking { tx: None, .. } => {
+                Err(io::Error::new(io::ErrorKind::BrokenPipe, "UI receiver dropped"))
+            }
+            #[cfg(feature = "async")]
+            ChannelTarget::Tokio { tx, dropped } => match tx.try_send(message) {
+                Ok(()) => Ok(()),
+                Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
+                    dropped.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }
+                Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => {
+                    Err(io::Error::new(io::ErrorKind::BrokenPipe, "Capture stream dropped"))
+                }
+            },
+        }
+    }
+}
+
+impl Drop for ChannelSink {
+    fn drop(&mut self) {
+        if let ChannelTarget::Bounded(queue) = &self.target {
+            queue.close();
+        }
+    }
+}
+
+impl MessageSink for ChannelSink {
+    fn write_record(&mut self, record: &BbinRecord) -> io::Result<()> {
+        let BbinRecord::Message { header, message, .. } = record else {
+            return Ok(());
+        };
+        self.send(LoggedMessage {
+            timestamp: header.timestamp,
+            system_id: header.system_id,
+            component_id: header.component_id,
+            source: header.source,
+            direction: header.direction,
+            message: message.clone(),
+            is_armed: self.armed.get(&header.system_id).copied().unwrap_or(false),
+            message_type: format!("{:?}", message),
+        }, true)
+    }
+
+    fn arm_state_changed(&mut self, message: &LoggedMessage) -> io::Result<()> {
+        self.armed.insert(message.system_id, message.is_armed);
+        self.send(message.clone(), false)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match &mut self.target {
+            ChannelTarget::Bounded(queue) => queue.close(),
+            ChannelTarget::Blocking { tx, .. } => drop(tx.take()),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Calls a closure with every record.
+pub struct CallbackSink<F>(pub F);
+
+impl<F> MessageSink for CallbackSink<F>
+where
+    F: FnMut(&BbinRecord) -> io::Result<()> + Send,
+{
+    fn write_record(&mut self, record: &BbinRecord) -> io::Result<()> {
+        (self.0)(record)
+    }
+}
+
+/// Sends every logged frame on to another link byte-for-byte, e.g. to feed a second
+/// GCS. Events are not sent, and neither are frames with a CRC mismatch, which may
+/// overlap a real frame that is sent on its own.
+impl MessageSink for Connection {
+    fn write_record(&mut self, record: &BbinRecord) -> io::Result<()> {
+        match record {
+            BbinRecord::Message { bytes, .. } => self.send_frame(bytes).map(drop),
+            BbinRecord::Undecoded { reason, bytes, .. } if *reason != UndecodedReason::CrcMismatch => self.send_frame(bytes).map(drop),
+            _ => Ok(()),
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
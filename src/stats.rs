@@ -0,0 +1,287 @@
+use crate::framer::RawFrame;
+use crate::types::UndecodedReason;
+use mavlink::ardupilotmega::MavMessage;
+use mavlink::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// How often rates are recomputed from the frames received since.
+pub(crate) const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Link health as seen from the frames received, per (system id, component id) and
+/// in total. Frames sent by the logger itself are not counted; duplicates dropped by
+/// `dedup_window` are not either.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrafficStats {
+    pub components: BTreeMap<(u8, u8), ComponentStats>,
+    pub frames: u64,
+    pub bytes: u64,
+    /// Over the last second, as are all rates.
+    pub bytes_per_sec: f64,
+    /// Frames whose CRC did not match. Their ids cannot be trusted, so they are not
+    /// counted against any component.
+    pub crc_errors: u64,
+    /// Frames with a trusted header that were kept undecoded, summed over components.
+    pub parse_errors: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ComponentStats {
+    pub frames: u64,
+    pub bytes: u64,
+    pub bytes_per_sec: f64,
+    /// Times the sequence number skipped ahead.
+    pub sequence_gaps: u64,
+    /// Frames missing according to those gaps.
+    pub lost: u64,
+    /// Frames that had an unknown message id or a payload that did not parse.
+    pub parse_errors: u64,
+    /// Decoded messages per message name, e.g. `ATTITUDE`.
+    pub messages: BTreeMap<String, MessageTypeStats>,
+}
+
+impl ComponentStats {
+    /// Estimated share of this component's frames that never arrived, from 0 to 1.
+    pub fn loss_ratio(&self) -> f64 {
+        let expected = self.frames + self.lost;
+        if expected == 0 { 0.0 } else { self.lost as f64 / expected as f64 }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MessageTypeStats {
+    pub count: u64,
+    pub rate_hz: f64,
+}
+
+impl TrafficStats {
+    /// Splits the snapshot into parts that each serialize to at most `max_len` bytes,
+    /// every one with the capture totals and a share of the components. A component
+    /// too large on its own still gets a part to itself.
+    pub(crate) fn split(&self, max_len: u64) -> bincode::Result<Vec<TrafficStats>> {
+        let totals = TrafficStats {
+            components: BTreeMap::new(),
+            frames: self.frames,
+            bytes: self.bytes,
+            bytes_per_sec: self.bytes_per_sec,
+            crc_errors: self.crc_errors,
+            parse_errors: self.parse_errors,
+        };
+        let base_len = bincode::serialized_size(&totals)?;
+        let mut parts = vec![totals.clone()];
+        let mut len = base_len;
+        for (key, component) in &self.components {
+            let entry_len = bincode::serialized_size(&(key, component))?;
+            if len + entry_len > max_len && len > base_len {
+                parts.push(totals.clone());
+                len = base_len;
+            }
+            if let Some(part) = parts.last_mut() {
+                part.components.insert(*key, component.clone());
+            }
+            len += entry_len;
+        }
+        Ok(parts)
+    }
+}
+
+/// Keeps the `TrafficStats` of a capture up to date as frames arrive.
+pub(crate) struct TrafficTracker {
+    stats: TrafficStats,
+    last_sequence: HashMap<(u8, u8), u8>,
+    /// Counts since `window_started`, turned into rates by `roll`.
+    window_bytes: u64,
+    window: HashMap<(u8, u8), ComponentWindow>,
+    window_started: Instant,
+}
+
+#[derive(Default)]
+struct ComponentWindow {
+    bytes: u64,
+    messages: HashMap<&'static str, u64>,
+}
+
+impl TrafficTracker {
+    pub(crate) fn new() -> Self {
+        TrafficTracker {
+            stats: TrafficStats::default(),
+            last_sequence: HashMap::new(),
+            window_bytes: 0,
+            window: HashMap::new(),
+            window_started: Instant::now(),
+        }
+    }
+
+    /// Counts a received frame, with its message if it decoded.
+    pub(crate) fn frame(&mut self, frame: &RawFrame, message: Option<&MavMessage>) {
+        let len = frame.bytes.len() as u64;
+        self.stats.frames += 1;
+        self.stats.bytes += len;
+        self.window_bytes += len;
+        if frame.defect == Some(UndecodedReason::CrcMismatch) {
+            self.stats.crc_errors += 1;
+            return;
+        }
+
+        let header = frame.header();
+        let key = (header.system_id, header.component_id);
+        let component = self.stats.components.entry(key).or_default();
+        component.frames += 1;
+        component.bytes += len;
+        // A jump of more than half the sequence space is taken as a reordered or
+        // repeated frame, e.g. from a second link, rather than a loss. Such a frame
+        // does not move the expected sequence either, so the next one is not a gap.
+        let skipped = self.last_sequence.get(&key).map(|last| header.sequence.wrapping_sub(last.wrapping_add(1)));
+        if skipped.is_none_or(|skipped| skipped < 128) {
+            if let Some(skipped @ 1..) = skipped {
+                component.sequence_gaps += 1;
+                component.lost += skipped as u64;
+            }
+            self.last_sequence.insert(key, header.sequence);
+        }
+
+        let window = self.window.entry(key).or_default();
+        window.bytes += len;
+        match message {
+            Some(message) => {
+                let name = message.message_name();
+                component.messages.entry(name.to_string()).or_default().count += 1;
+                *window.messages.entry(name).or_default() += 1;
+            }
+            None => {
+                component.parse_errors += 1;
+                self.stats.parse_errors += 1;
+            }
+        }
+    }
+
+    /// When the rates are next due to be recomputed.
+    pub(crate) fn next_roll(&self) -> Instant {
+        self.window_started + RATE_WINDOW
+    }
+
+    /// Recomputes the rates once `RATE_WINDOW` has passed. Returns whether it did.
+    pub(crate) fn roll(&mut self, now: Instant) -> bool {
+        if now < self.next_roll() {
+            return false;
+        }
+        let secs = now.duration_since(self.window_started).as_secs_f64();
+        self.stats.bytes_per_sec = self.window_bytes as f64 / secs;
+        for (key, component) in &mut self.stats.components {
+            let window = self.window.get(key);
+            component.bytes_per_sec = window.map_or(0, |w| w.bytes) as f64 / secs;
+            for (name, message) in &mut component.messages {
+                let count = window.and_then(|w| w.messages.get(name.as_str())).copied().unwrap_or(0);
+                message.rate_hz = count as f64 / secs;
+            }
+        }
+        self.window_bytes = 0;
+        self.window.clear();
+        self.window_started = now;
+        true
+    }
+
+    pub(crate) fn stats(&self) -> &TrafficStats {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framer::MavFramer;
+    use mavlink::ardupilotmega::HEARTBEAT_DATA;
+    use mavlink::{write_v2_msg, MavHeader};
+
+    fn heartbeat(system_id: u8, sequence: u8) -> (RawFrame, MavMessage) {
+        let message = MavMessage::HEARTBEAT(HEARTBEAT_DATA::default());
+        let mut bytes = Vec::new();
+        write_v2_msg(&mut bytes, MavHeader { sequence, system_id, component_id: 1 }, &message).unwrap();
+        let mut framer = MavFramer::new();
+        framer.push(&bytes);
+        (framer.next_frame().unwrap(), message)
+    }
+
+    fn track(sequences: &[u8]) -> ComponentStats {
+        let mut tracker = TrafficTracker::new();
+        for &sequence in sequences {
+            let (frame, message) = heartbeat(1, sequence);
+            tracker.frame(&frame, Some(&message));
+        }
+        tracker.stats().components[&(1, 1)].clone()
+    }
+
+    #[test]
+    fn counts_sequence_gaps() {
+        let stats = track(&[0, 1, 4, 5, 7]);
+        assert_eq!((stats.frames, stats.sequence_gaps, stats.lost), (5, 2, 3));
+        assert_eq!(stats.messages["HEARTBEAT"].count, 5);
+        assert!((stats.loss_ratio() - 3.0 / 8.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sequence_wraps_at_255() {
+        let stats = track(&[254, 255, 0, 1]);
+        assert_eq!((stats.sequence_gaps, stats.lost), (0, 0));
+        let stats = track(&[254, 1]);
+        assert_eq!((stats.sequence_gaps, stats.lost), (1, 2));
+    }
+
+    #[test]
+    fn repeated_frame_from_second_link_is_not_a_loss() {
+        // The second link delivers frame 1 again after frame 2 from the first.
+        let stats = track(&[0, 1, 2, 1, 3, 4]);
+        assert_eq!(stats.frames, 6);
+        assert_eq!((stats.sequence_gaps, stats.lost), (0, 0));
+    }
+
+    #[test]
+    fn components_are_tracked_apart() {
+        let mut tracker = TrafficTracker::new();
+        for (system_id, sequence) in [(1, 0), (2, 100), (1, 1), (2, 101)] {
+            let (frame, message) = heartbeat(system_id, sequence);
+            tracker.frame(&frame, Some(&message));
+        }
+        let stats = tracker.stats();
+        assert_eq!(stats.frames, 4);
+        assert!(stats.components.values().all(|component| component.frames == 2 && component.lost == 0));
+    }
+
+    #[test]
+    fn splits_large_snapshots_by_component() {
+        let mut stats = TrafficStats { frames: 1_000, bytes: 50_000, ..Default::default() };
+        for system_id in 0..20 {
+            let component = stats.components.entry((system_id, 1)).or_default();
+            for name in ["ATTITUDE", "GLOBAL_POSITION_INT", "RAW_IMU", "SYS_STATUS", "VFR_HUD"] {
+                component.messages.insert(name.to_string(), MessageTypeStats { count: 10, rate_hz: 5.0 });
+            }
+        }
+        let whole_len = bincode::serialized_size(&stats).unwrap();
+        assert_eq!(stats.split(whole_len).unwrap().len(), 1);
+
+        let parts = stats.split(whole_len / 3).unwrap();
+        assert!(parts.len() >= 3);
+        let mut components = BTreeMap::new();
+        for part in parts {
+            assert!(bincode::serialized_size(&part).unwrap() <= whole_len / 3);
+            assert_eq!((part.frames, part.bytes), (1_000, 50_000));
+            assert!(!part.components.is_empty());
+            components.extend(part.components);
+        }
+        assert_eq!(components.keys().collect::<Vec<_>>(), stats.components.keys().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn crc_errors_are_not_counted_against_a_component() {
+        let mut tracker = TrafficTracker::new();
+        let (mut frame, _) = heartbeat(1, 0);
+        frame.defect = Some(UndecodedReason::CrcMismatch);
+        tracker.frame(&frame, None);
+        let (frame, _) = heartbeat(1, 1);
+        tracker.frame(&frame, None);
+        let stats = tracker.stats();
+        assert_eq!((stats.frames, stats.crc_errors, stats.parse_errors), (2, 1, 1));
+        assert_eq!(stats.components[&(1, 1)].frames, 1);
+    }
+}
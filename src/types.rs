@@ -1,5 +1,112 @@
-use mavlink::MavHeader;
+use crate::error::BlackboxError;
+use crate::framer::RawFrame;
+use crate::stats::TrafficStats;
+use mavlink::ardupilotmega::MavMessage;
+use mavlink::{read_versioned_msg, MavHeader, MavlinkVersion, MAV_STX};
 use serde::{Serialize, Deserialize};
+use std::fmt;
+
+/// BBIN format version written by `BbinWriter`.
+pub const BBIN_VERSION: u16 = 18; // 1.8, added statistics records
+
+/// Oldest BBIN format version the readers still understand, besides
+/// `BBIN_LEGACY_VERSION`. 1.6 added the previous segment to the file header.
+pub const BBIN_MIN_VERSION: u16 = 16; // 1.6
+
+/// Format written by blackboxer 0.1.4 and earlier, which the readers still accept.
+/// Its file header has no previous segment, its record headers carry no protocol
+/// version, kind, source or direction, and its index ends without `BBIN_INDEX_MAGIC`.
+/// Its records are read as MAVLink v2 messages received on source 0, downlink.
+pub const BBIN_LEGACY_VERSION: u16 = 10; // 1.0
+
+/// Written after the index offset when a file is finalized, so readers can tell
+/// a complete file from one whose capture was cut short.
+pub const BBIN_INDEX_MAGIC: [u8; 4] = *b"BIDX";
+
+/// What the bytes following a `LoggedMessageHeader` contain.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    /// A frame that decoded as a known MAVLink message.
+    Message,
+    /// A frame kept as raw bytes because it could not be decoded.
+    Undecoded(UndecodedReason),
+    /// Something that happened to the capture itself; the bytes are a UTF-8 detail string.
+    Event(CaptureEvent),
+    /// A periodic `TrafficStats` snapshot, bincode-encoded. A snapshot too large for
+    /// one record is split over several with the same timestamp, each holding the
+    /// totals and some of the components.
+    Stats,
+}
+
+/// Which way a frame was travelling when it was captured.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the vehicle towards the ground; all traffic outside proxy mode.
+    Downlink,
+    /// From a GCS towards the vehicle, seen in proxy mode.
+    Uplink,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Downlink => write!(f, "downlink"),
+            Direction::Uplink => write!(f, "uplink"),
+        }
+    }
+}
+
+/// Capture events recorded in the log alongside the traffic.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureEvent {
+    /// The link to the endpoint failed.
+    LinkLost,
+    /// The link was re-established after `LinkLost`.
+    LinkRestored,
+    /// An inbound connection was accepted; the detail is the peer address and the
+    /// record's `source` is the id its traffic is logged under.
+    SourceConnected,
+    /// An inbound connection closed. Its source id is not reused until the ids wrap,
+    /// and then only if no live link holds it.
+    SourceDisconnected,
+    /// Recording was paused; nothing is logged until `Resumed`.
+    Paused,
+    Resumed,
+}
+
+impl fmt::Display for CaptureEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureEvent::LinkLost => write!(f, "link lost"),
+            CaptureEvent::LinkRestored => write!(f, "link restored"),
+            CaptureEvent::SourceConnected => write!(f, "source connected"),
+            CaptureEvent::SourceDisconnected => write!(f, "source disconnected"),
+            CaptureEvent::Paused => write!(f, "paused"),
+            CaptureEvent::Resumed => write!(f, "resumed"),
+        }
+    }
+}
+
+/// Why a captured frame could not be decoded.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum UndecodedReason {
+    /// The message id is not part of the dialect, so its CRC could not be checked.
+    UnknownMessage,
+    /// The frame checksum did not match its contents.
+    CrcMismatch,
+    /// The CRC matched but the payload could not be parsed (bad length, invalid enum value, ...).
+    ParseError,
+}
+
+impl fmt::Display for UndecodedReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UndecodedReason::UnknownMessage => write!(f, "unknown message id"),
+            UndecodedReason::CrcMismatch => write!(f, "CRC mismatch"),
+            UndecodedReason::ParseError => write!(f, "payload parse error"),
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LoggedMessageHeader {
@@ -8,33 +115,137 @@ pub struct LoggedMessageHeader {
     pub system_id: u8,
     pub component_id: u8,
     pub msg_len: u16,
+    pub protocol_version: u8, // 1 or 2, the MAVLink version the frame was received in
+    pub kind: RecordKind,
+    pub source: u8, // capture link the frame arrived on, 0 for a single dial-out link
+    pub direction: Direction,
 }
 
 impl LoggedMessageHeader {
-    pub fn from_mav_header(timestamp: i64, header: MavHeader, msg_len: usize) -> Self {
+    /// Header for a record holding `raw_msg_bytes` from link `source`. The protocol
+    /// version is taken from the frame's STX marker.
+    pub fn new(timestamp: i64, source: u8, direction: Direction, header: MavHeader, kind: RecordKind, raw_msg_bytes: &[u8]) -> Self {
+        let version = if raw_msg_bytes.first() == Some(&MAV_STX) { MavlinkVersion::V1 } else { MavlinkVersion::V2 };
+        let mut logged_header = Self::from_mav_header(timestamp, header, version, raw_msg_bytes.len());
+        logged_header.kind = kind;
+        logged_header.source = source;
+        logged_header.direction = direction;
+        logged_header
+    }
+
+    pub fn from_mav_header(timestamp: i64, header: MavHeader, version: MavlinkVersion, msg_len: usize) -> Self {
         Self {
+            kind: RecordKind::Message,
+            source: 0,
+            direction: Direction::Downlink,
             timestamp,
             sequence: header.sequence,
             system_id: header.system_id,
             component_id: header.component_id,
             msg_len: msg_len as u16,
+            protocol_version: match version {
+                MavlinkVersion::V1 => 1,
+                MavlinkVersion::V2 => 2,
+            },
         }
     }
+
+    pub fn mavlink_version(&self) -> MavlinkVersion {
+        if self.protocol_version == 1 { MavlinkVersion::V1 } else { MavlinkVersion::V2 }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct LoggedMessage {
     pub timestamp: i64,
-    pub message: mavlink::ardupilotmega::MavMessage,
+    pub system_id: u8,
+    pub component_id: u8,
+    /// Capture link the message arrived on, as in `LoggedMessageHeader::source`.
+    pub source: u8,
+    pub direction: Direction,
+    pub message: MavMessage,
     pub is_armed: bool,
     pub message_type: String,
 }
 
-#[derive(Serialize, Deserialize)]
+/// One record as handed to a `MessageSink` or read back from a BBIN file, together
+/// with the frame bytes it holds.
+// Records are read one at a time, so the size of `MavMessage` is not worth boxing.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone)]
+pub enum BbinRecord {
+    Message {
+        header: LoggedMessageHeader,
+        message: MavMessage,
+        bytes: Vec<u8>,
+    },
+    Undecoded {
+        header: LoggedMessageHeader,
+        reason: UndecodedReason,
+        bytes: Vec<u8>,
+    },
+    Event {
+        header: LoggedMessageHeader,
+        event: CaptureEvent,
+        bytes: Vec<u8>,
+    },
+    Stats {
+        header: LoggedMessageHeader,
+        stats: TrafficStats,
+        bytes: Vec<u8>,
+    },
+}
+
+impl BbinRecord {
+    pub fn header(&self) -> &LoggedMessageHeader {
+        match self {
+            BbinRecord::Message { header, .. }
+            | BbinRecord::Undecoded { header, .. }
+            | BbinRecord::Event { header, .. }
+            | BbinRecord::Stats { header, .. } => header,
+        }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            BbinRecord::Message { bytes, .. }
+            | BbinRecord::Undecoded { bytes, .. }
+            | BbinRecord::Event { bytes, .. }
+            | BbinRecord::Stats { bytes, .. } => bytes,
+        }
+    }
+
+    /// The decoded message, or why the record does not hold one: a CRC mismatch,
+    /// an unknown message id, the parser's error, or a record that holds no frame.
+    pub fn decode(&self) -> Result<MavMessage, BlackboxError> {
+        match self {
+            BbinRecord::Message { message, .. } => Ok(message.clone()),
+            BbinRecord::Undecoded { header, reason, bytes } => {
+                let frame = RawFrame { version: header.mavlink_version(), bytes: bytes.clone(), defect: Some(*reason) };
+                // Only a damaged log holds a frame too short to carry a message id.
+                let has_id = bytes.len() >= if frame.version == MavlinkVersion::V1 { 6 } else { 10 };
+                match reason {
+                    _ if !has_id => read_versioned_msg::<MavMessage, _>(&mut bytes.as_slice(), frame.version)
+                        .map(|(_, message)| message)
+                        .map_err(BlackboxError::Decode),
+                    UndecodedReason::CrcMismatch => Err(BlackboxError::CrcMismatch { message_id: frame.message_id() }),
+                    UndecodedReason::UnknownMessage => Err(BlackboxError::UnknownMessage(frame.message_id())),
+                    UndecodedReason::ParseError => read_versioned_msg::<MavMessage, _>(&mut bytes.as_slice(), frame.version)
+                        .map(|(_, message)| message)
+                        .map_err(BlackboxError::Decode),
+                }
+            }
+            BbinRecord::Event { header, .. } | BbinRecord::Stats { header, .. } => Err(BlackboxError::NotAMessage(header.kind)),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BbinHeader {
     pub magic: [u8; 4], // "BBIN"
-    pub version: u16,   // e.g., 1.0 as 10
+    pub version: u16,   // e.g., 1.0 as 10, see BBIN_VERSION
     pub start_timestamp: i64, // Unix timestamp in milliseconds
+    pub previous_segment: Option<String>, // file name of the segment this one continues after rotation
 }
 
 #[derive(Serialize, Deserialize)]
@@ -0,0 +1,286 @@
+use crate::error::BlackboxError;
+use crate::types::LoggedMessage;
+use mavlink::Message;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::str::FromStr;
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// What a bounded `ChannelSink` does when the UI has fallen behind and its queue is full.
+///
+/// Parses from `block`, `drop-oldest`, `drop-newest` or `decimate:<millis>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UiOverflow {
+    /// Wait until the UI makes room. The waiting happens on a delivery thread, so the
+    /// capture keeps logging and forwarding; up to another `capacity` messages line up
+    /// for that thread, and any beyond that are discarded as with `DropNewest`.
+    Block,
+    /// Discard the oldest queued message to make room.
+    DropOldest,
+    /// Discard the message being delivered.
+    DropNewest,
+    /// Deliver each message type from each component at most once per interval, so a
+    /// 50 Hz ATTITUDE stream cannot crowd out everything else. Arm state changes always go through; a message
+    /// that still finds the queue full is discarded as with `DropNewest`.
+    Decimate(Duration),
+}
+
+impl FromStr for UiOverflow {
+    type Err = BlackboxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || BlackboxError::InvalidConfig(
+            format!("UI overflow must be block, drop-oldest, drop-newest or decimate:<millis>: {}", s),
+        );
+        match s {
+            "block" => Ok(UiOverflow::Block),
+            "drop-oldest" => Ok(UiOverflow::DropOldest),
+            "drop-newest" => Ok(UiOverflow::DropNewest),
+            _ => {
+                let millis = s.strip_prefix("decimate:").and_then(|millis| millis.parse().ok()).ok_or_else(invalid)?;
+                Ok(UiOverflow::Decimate(Duration::from_millis(millis)))
+            }
+        }
+    }
+}
+
+/// Counters of a bounded UI queue.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UiQueueStats {
+    /// Messages handed to the UI.
+    pub delivered: u64,
+    /// Messages discarded because the queue was full.
+    pub dropped: u64,
+    /// Messages skipped by `UiOverflow::Decimate`.
+    pub decimated: u64,
+    /// Messages waiting in the queue right now, including those lined up for the
+    /// delivery thread of `UiOverflow::Block`.
+    pub queued: usize,
+}
+
+/// Shared between the `ChannelSink` filling it and the `UiReceiver` draining it.
+pub(crate) struct UiQueue {
+    state: Mutex<QueueState>,
+    changed: Condvar,
+    capacity: usize,
+    overflow: UiOverflow,
+}
+
+struct QueueState {
+    messages: VecDeque<LoggedMessage>,
+    stats: UiQueueStats,
+    /// Timestamp of the last message queued per (system id, component id, message id),
+    /// for `Decimate`.
+    last_queued: HashMap<(u8, u8, u32), i64>,
+    /// Messages handed to the delivery thread of `UiOverflow::Block` and not queued yet.
+    in_transit: usize,
+    sender_alive: bool,
+    receiver_alive: bool,
+}
+
+impl UiQueue {
+    pub(crate) fn new(capacity: usize, overflow: UiOverflow) -> Arc<Self> {
+        Arc::new(UiQueue {
+            state: Mutex::new(QueueState {
+                messages: VecDeque::new(),
+                stats: UiQueueStats::default(),
+                last_queued: HashMap::new(),
+                in_transit: 0,
+                sender_alive: true,
+                receiver_alive: true,
+            }),
+            changed: Condvar::new(),
+            capacity: capacity.max(1),
+            overflow,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Queues `message` under the overflow policy. `decimate` is false for messages
+    /// that must not be thinned out. Fails once the receiver has been dropped.
+    pub(crate) fn push(&self, message: LoggedMessage, decimate: bool) -> io::Result<()> {
+        self.insert(message, decimate, false)
+    }
+
+    /// Lines `message` up for the delivery thread of `UiOverflow::Block` without
+    /// waiting, or counts it as dropped if `capacity` messages are already lined up.
+    pub(crate) fn hand_off(&self, tx: &SyncSender<LoggedMessage>, message: LoggedMessage) -> io::Result<()> {
+        let mut state = self.lock();
+        match tx.try_send(message) {
+            Ok(()) => state.in_transit += 1,
+            Err(TrySendError::Full(_)) => state.stats.dropped += 1,
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "UI receiver dropped"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Queues a message taken from the hand-off on the delivery thread, waiting for room.
+    pub(crate) fn deliver(&self, message: LoggedMessage) -> io::Result<()> {
+        self.insert(message, false, true)
+    }
+
+    fn insert(&self, message: LoggedMessage, decimate: bool, handed_off: bool) -> io::Result<()> {
+        let receiver_dropped = || io::Error::new(io::ErrorKind::BrokenPipe, "UI receiver dropped");
+        let mut state = self.lock();
+        if !state.receiver_alive {
+            return Err(receiver_dropped());
+        }
+        if let (UiOverflow::Decimate(interval), true) = (self.overflow, decimate) {
+            let key = (message.system_id, message.component_id, message.message.message_id());
+            let interval = interval.as_millis() as i64;
+            if state.last_queued.get(&key).is_some_and(|&last| message.timestamp - last < interval) {
+                state.stats.decimated += 1;
+                return Ok(());
+            }
+            state.last_queued.insert(key, message.timestamp);
+        }
+        while state.messages.len() >= self.capacity {
+            match self.overflow {
+                UiOverflow::Block => {
+                    state = self.changed.wait(state).unwrap_or_else(PoisonError::into_inner);
+                    if !state.receiver_alive {
+                        return Err(receiver_dropped());
+                    }
+                }
+                UiOverflow::DropOldest => {
+                    state.messages.pop_front();
+                    state.stats.dropped += 1;
+                }
+                UiOverflow::DropNewest | UiOverflow::Decimate(_) => {
+                    state.stats.dropped += 1;
+                    return Ok(());
+                }
+            }
+        }
+        if handed_off {
+            state.in_transit -= 1;
+        }
+        state.messages.push_back(message);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Lets the receiver drain what is left and then see the end of the capture.
+    pub(crate) fn close(&self) {
+        self.lock().sender_alive = false;
+        self.changed.notify_all();
+    }
+
+    fn pop(&self, deadline: Option<Instant>) -> Option<LoggedMessage> {
+        let mut state = self.lock();
+        loop {
+            if let Some(message) = state.messages.pop_front() {
+                state.stats.delivered += 1;
+                self.changed.notify_all();
+                return Some(message);
+            }
+            if !state.sender_alive {
+                return None;
+            }
+            state = match deadline {
+                None => self.changed.wait(state).unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return None;
+                    }
+                    self.changed.wait_timeout(state, deadline - now).unwrap_or_else(PoisonError::into_inner).0
+                }
+            };
+        }
+    }
+}
+
+/// The UI end of a bounded `ChannelSink`, created by `ChannelSink::bounded`.
+pub struct UiReceiver {
+    pub(crate) queue: Arc<UiQueue>,
+}
+
+impl UiReceiver {
+    /// Waits for the next message. Returns `None` once the capture has ended and
+    /// everything queued has been received.
+    pub fn recv(&self) -> Option<LoggedMessage> {
+        self.queue.pop(None)
+    }
+
+    /// Like `recv`, but gives up after `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<LoggedMessage> {
+        self.queue.pop(Some(Instant::now() + timeout))
+    }
+
+    pub fn try_recv(&self) -> Option<LoggedMessage> {
+        self.queue.pop(Some(Instant::now()))
+    }
+
+    pub fn stats(&self) -> UiQueueStats {
+        let state = self.queue.lock();
+        UiQueueStats { queued: state.messages.len() + state.in_transit, ..state.stats }
+    }
+}
+
+impl Iterator for UiReceiver {
+    type Item = LoggedMessage;
+
+    fn next(&mut self) -> Option<LoggedMessage> {
+        self.recv()
+    }
+}
+
+impl Drop for UiReceiver {
+    fn drop(&mut self) {
+        self.queue.lock().receiver_alive = false;
+        self.queue.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Direction;
+    use mavlink::ardupilotmega::{MavMessage, ATTITUDE_DATA};
+
+    fn attitude(timestamp: i64, system_id: u8) -> LoggedMessage {
+        LoggedMessage {
+            timestamp,
+            system_id,
+            component_id: 1,
+            source: 0,
+            direction: Direction::Downlink,
+            message: MavMessage::ATTITUDE(ATTITUDE_DATA::default()),
+            is_armed: false,
+            message_type: "ATTITUDE".to_string(),
+        }
+    }
+
+    #[test]
+    fn decimates_each_vehicle_on_its_own() {
+        let queue = UiQueue::new(100, UiOverflow::Decimate(Duration::from_millis(100)));
+        for timestamp in (0..1000).step_by(20) {
+            queue.push(attitude(timestamp, 1), true).unwrap();
+            queue.push(attitude(timestamp + 5, 2), true).unwrap();
+        }
+        let receiver = UiReceiver { queue };
+        let stats = receiver.stats();
+        assert_eq!(stats.queued, 20);
+        assert_eq!(stats.decimated, 80);
+        let from_second = std::iter::from_fn(|| receiver.try_recv()).filter(|message| message.system_id == 2).count();
+        assert_eq!(from_second, 10);
+    }
+
+    #[test]
+    fn arm_changes_are_not_decimated() {
+        let queue = UiQueue::new(100, UiOverflow::Decimate(Duration::from_secs(1)));
+        queue.push(attitude(0, 1), true).unwrap();
+        queue.push(attitude(1, 1), false).unwrap();
+        queue.push(attitude(2, 1), true).unwrap();
+        let stats = UiReceiver { queue }.stats();
+        assert_eq!((stats.queued, stats.decimated), (2, 1));
+    }
+}
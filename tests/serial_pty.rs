@@ -0,0 +1,59 @@
+//! Captures from a `serial:` endpoint backed by a pseudo-terminal, so the serial
+//! path is exercised without hardware.
+#![cfg(target_os = "linux")]
+
+use blackboxer::{BbinReader, BlackBoxer, BlackBoxerConfig};
+use mavlink::ardupilotmega::{MavAutopilot, MavMessage, MavType, HEARTBEAT_DATA};
+use mavlink::{write_v2_msg, MavHeader};
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+
+/// Opens a pty pair and returns the master end and the path of the slave end. The
+/// slave fd is returned too, so the pty stays open until the capture has opened it.
+fn open_pty() -> (File, OwnedFd, String) {
+    let (mut master, mut slave) = (0, 0);
+    let result = unsafe {
+        libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null(), std::ptr::null())
+    };
+    assert_eq!(result, 0, "openpty failed: {}", std::io::Error::last_os_error());
+    let path = unsafe { CStr::from_ptr(libc::ttyname(slave)) }.to_str().unwrap().to_string();
+    unsafe { (File::from_raw_fd(master), OwnedFd::from_raw_fd(slave), path) }
+}
+
+#[test]
+fn captures_heartbeat_from_serial_port() {
+    let (mut master, _slave, path) = open_pty();
+    let output_dir = std::env::temp_dir().join(format!("blackboxer-serial-pty-{}", std::process::id()));
+    let config = BlackBoxerConfig {
+        addrs: vec![format!("serial:{}:57600", path)],
+        output_dir: output_dir.clone(),
+        file_template: "serial".to_string(),
+        stats_interval: None,
+        ..Default::default()
+    };
+    let capture = BlackBoxer::new(config).unwrap().start().unwrap();
+
+    let heartbeat = MavMessage::HEARTBEAT(HEARTBEAT_DATA {
+        mavtype: MavType::MAV_TYPE_QUADROTOR,
+        autopilot: MavAutopilot::MAV_AUTOPILOT_ARDUPILOTMEGA,
+        ..Default::default()
+    });
+    write_v2_msg(&mut master, MavHeader { sequence: 0, system_id: 1, component_id: 1 }, &heartbeat).unwrap();
+    master.flush().unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while capture.stats().frames_written == 0 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    let summary = capture.stop().unwrap();
+    assert_eq!(summary.files.len(), 1);
+
+    let messages = BbinReader::new(summary.files[0].to_str().unwrap()).unwrap().read_and_collect(None).unwrap();
+    fs::remove_dir_all(&output_dir).unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].system_id, 1);
+    assert_eq!(messages[0].message, heartbeat);
+}